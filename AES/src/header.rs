@@ -0,0 +1,519 @@
+use std::io::{self, Read};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use aes_gcm_siv::Aes256GcmSiv;
+use common::stream::{ChunkDecryptor, ChunkEncryptor};
+
+/// Magic header of the original single-shot format: salt, nonce, then one ciphertext.
+pub const LEGACY_MAGIC: &[u8; 9] = b"SECUREENC";
+
+/// Magic header of the chunked streaming format.
+pub const STREAM_MAGIC: &[u8; 9] = b"SECUREENS";
+
+/// Version of the streaming format.
+pub const VERSION: u8 = 1;
+
+/// Key derivation function identifiers.
+pub const KDF_ARGON2ID: u8 = 1;
+
+/// Payload cipher identifiers.
+pub const CIPHER_AES256GCM: u8 = 1;
+pub const CIPHER_AES256GCMSIV: u8 = 2;
+
+/// Cipher used for the payload chunks. The data key is always wrapped with
+/// AES-256-GCM; only the payload cipher is selectable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256-GCM.
+    Aes256Gcm,
+    /// AES-256-GCM-SIV, which stays secure even if a nonce is ever repeated.
+    Aes256GcmSiv,
+}
+
+impl Cipher {
+    pub fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => CIPHER_AES256GCM,
+            Cipher::Aes256GcmSiv => CIPHER_AES256GCMSIV,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        match id {
+            CIPHER_AES256GCM => Ok(Cipher::Aes256Gcm),
+            CIPHER_AES256GCMSIV => Ok(Cipher::Aes256GcmSiv),
+            _ => Err(format!("Unsupported cipher {}.", id).into()),
+        }
+    }
+
+    /// STREAM encryptor for the payload chunks. Both ciphers use 12-byte
+    /// nonces, so the nonce prefix layout is identical.
+    pub fn encryptor(self, key: &[u8; 32], nonce_prefix: &[u8; NONCE_PREFIX_LEN]) -> Box<dyn ChunkEncryptor> {
+        let (key, nonce) = (GenericArray::from_slice(key), GenericArray::from_slice(nonce_prefix));
+        match self {
+            Cipher::Aes256Gcm => Box::new(EncryptorBE32::from_aead(Aes256Gcm::new(key), nonce)),
+            Cipher::Aes256GcmSiv => Box::new(EncryptorBE32::from_aead(Aes256GcmSiv::new(key), nonce)),
+        }
+    }
+
+    /// STREAM decryptor counterpart of `encryptor`.
+    pub fn decryptor(self, key: &[u8; 32], nonce_prefix: &[u8; NONCE_PREFIX_LEN]) -> Box<dyn ChunkDecryptor> {
+        let (key, nonce) = (GenericArray::from_slice(key), GenericArray::from_slice(nonce_prefix));
+        match self {
+            Cipher::Aes256Gcm => Box::new(DecryptorBE32::from_aead(Aes256Gcm::new(key), nonce)),
+            Cipher::Aes256GcmSiv => Box::new(DecryptorBE32::from_aead(Aes256GcmSiv::new(key), nonce)),
+        }
+    }
+}
+
+/// Header flag: the payload starts with an encrypted metadata block
+/// (see `common::metadata::Metadata`).
+pub const FLAG_METADATA: u8 = 0x01;
+
+/// Header flag: the payload (after the metadata block) is a
+/// directory tree in the archive format of `archive::pack`.
+pub const FLAG_ARCHIVE: u8 = 0x02;
+
+/// Key slot flag: the slot's key was derived from the password together with a keyfile.
+pub const SLOT_FLAG_KEYFILE: u8 = 0x01;
+
+/// Number of key slots in a header. The slot table has a fixed
+/// size so that slots can be added or removed by rewriting the header in place.
+pub const MAX_SLOTS: usize = 8;
+
+/// Length of the salt fed to the key derivation function.
+pub const SALT_LEN: usize = 16;
+
+/// Length of the random data-encryption key that encrypts the payload.
+pub const DATA_KEY_LEN: usize = 32;
+
+/// Length of the AES-GCM nonce used to wrap the data-encryption key in a slot.
+pub const WRAP_NONCE_LEN: usize = 12;
+
+/// Length of a wrapped data-encryption key: the key plus the AES-GCM tag.
+pub const WRAPPED_KEY_LEN: usize = DATA_KEY_LEN + 16;
+
+/// Length of the per-file STREAM nonce prefix. The remaining 5 bytes of the
+/// 12-byte nonce hold the 32-bit chunk counter and the last-chunk flag.
+pub const NONCE_PREFIX_LEN: usize = 7;
+
+/// Largest chunk size accepted when reading a file, so a corrupted header
+/// cannot make us allocate an arbitrary amount of memory.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+// Slot state markers.
+const SLOT_EMPTY: u8 = 0;
+const SLOT_ACTIVE: u8 = 1;
+
+/// Length of a slot after its state byte: flags, KDF parameters, salt, wrap
+/// nonce and wrapped key.
+const SLOT_BODY_LEN: usize = 1 + 13 + SALT_LEN + WRAP_NONCE_LEN + WRAPPED_KEY_LEN;
+
+// Bounds on the Argon2id parameters accepted from a file header. They leave
+// room to raise the cost of new files while refusing values that would
+// exhaust memory or hang before the password could even be checked.
+const MIN_MEMORY_KIB: u32 = 8192;
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MIN_ITERATIONS: u32 = 1;
+const MAX_ITERATIONS: u32 = 64;
+const MIN_LANES: u32 = 1;
+const MAX_LANES: u32 = 64;
+
+/// Argon2id cost parameters recorded in each file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over the memory.
+    pub iterations: u32,
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+impl KdfParams {
+    /// Parameters used for newly encrypted files (64 MiB, 3 iterations, 4 lanes).
+    pub const DEFAULT: KdfParams = KdfParams { memory_kib: 65536, iterations: 3, lanes: 4 };
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self, Box<dyn std::error::Error>> {
+        let kdf_id = read_u8(reader)?;
+        if kdf_id != KDF_ARGON2ID {
+            return Err(format!("Unsupported key derivation function {}.", kdf_id).into());
+        }
+        let kdf = KdfParams {
+            memory_kib: read_u32(reader)?,
+            iterations: read_u32(reader)?,
+            lanes: read_u32(reader)?,
+        };
+        kdf.validate()?;
+        Ok(kdf)
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        out.push(KDF_ARGON2ID);
+        out.extend_from_slice(&self.memory_kib.to_be_bytes());
+        out.extend_from_slice(&self.iterations.to_be_bytes());
+        out.extend_from_slice(&self.lanes.to_be_bytes());
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !(MIN_MEMORY_KIB..=MAX_MEMORY_KIB).contains(&self.memory_kib) {
+            return Err(format!("Argon2 memory cost {} KiB in file header is out of range.", self.memory_kib).into());
+        }
+        if !(MIN_ITERATIONS..=MAX_ITERATIONS).contains(&self.iterations) {
+            return Err(format!("Argon2 iteration count {} in file header is out of range.", self.iterations).into());
+        }
+        if !(MIN_LANES..=MAX_LANES).contains(&self.lanes) {
+            return Err(format!("Argon2 lane count {} in file header is out of range.", self.lanes).into());
+        }
+        Ok(())
+    }
+}
+
+/// The data-encryption key wrapped under a password-derived key.
+#[derive(Clone, Debug)]
+pub struct WrappedKey {
+    pub nonce: [u8; WRAP_NONCE_LEN],
+    pub ciphertext: [u8; WRAPPED_KEY_LEN],
+}
+
+/// One way of obtaining the payload key from a password (and optional
+/// keyfile): the random data-encryption key, wrapped under a key derived from
+/// them.
+#[derive(Clone, Debug)]
+pub struct KeySlot {
+    pub flags: u8,
+    pub kdf: KdfParams,
+    pub salt: [u8; SALT_LEN],
+    pub wrapped: WrappedKey,
+}
+
+impl KeySlot {
+    /// Whether this slot requires a keyfile in addition to the password.
+    pub fn requires_keyfile(&self) -> bool {
+        self.flags & SLOT_FLAG_KEYFILE != 0
+    }
+
+    /// Serializes the slot parameters that are authenticated when wrapping
+    /// the data-encryption key: flags, KDF parameters and salt.
+    fn encode_params_into(&self, out: &mut Vec<u8>) {
+        out.push(self.flags);
+        self.kdf.encode_into(out);
+        out.extend_from_slice(&self.salt);
+    }
+}
+
+/// Header of the streaming format.
+///
+/// Layout (all integers big-endian):
+/// [magic (9)][version (1)][cipher (1)][flags (1)][nonce prefix (7)][chunk size (4)]
+/// [slot count (1)] followed by `slot count` key slots of
+/// [state (1)][flags (1)][kdf (1)][memory KiB (4)][iterations (4)][lanes (4)]
+/// [salt (16)][wrap nonce (12)][wrapped key (48)]
+/// Empty slots are all zeros. Chunks are authenticated against everything
+/// before the slot count, so the slot table can change without touching them.
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub cipher: Cipher,
+    pub flags: u8,
+    pub nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    pub chunk_size: u32,
+    pub slots: Vec<Option<KeySlot>>,
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_chunk_size<R: Read>(reader: &mut R) -> Result<u32, Box<dyn std::error::Error>> {
+    let chunk_size = read_u32(reader)?;
+    if chunk_size == 0 || chunk_size as usize > MAX_CHUNK_SIZE {
+        return Err("Invalid chunk size in file header.".into());
+    }
+    Ok(chunk_size)
+}
+
+impl Header {
+    /// Creates a header with an empty slot table.
+    pub fn new(cipher: Cipher, nonce_prefix: [u8; NONCE_PREFIX_LEN], chunk_size: u32) -> Self {
+        Header {
+            cipher,
+            flags: 0,
+            nonce_prefix,
+            chunk_size,
+            slots: vec![None; MAX_SLOTS],
+        }
+    }
+
+    /// Serializes the fixed part of the header, which every chunk is
+    /// authenticated against.
+    fn encode_prefix(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(23);
+        out.extend_from_slice(STREAM_MAGIC);
+        out.push(VERSION);
+        out.push(self.cipher.id());
+        out.push(self.flags);
+        out.extend_from_slice(&self.nonce_prefix);
+        out.extend_from_slice(&self.chunk_size.to_be_bytes());
+        out
+    }
+
+    /// Serializes the complete header, including the magic and the slot table.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.encode_prefix();
+        out.push(self.slots.len() as u8);
+        for slot in &self.slots {
+            match slot {
+                Some(slot) => {
+                    out.push(SLOT_ACTIVE);
+                    slot.encode_params_into(&mut out);
+                    out.extend_from_slice(&slot.wrapped.nonce);
+                    out.extend_from_slice(&slot.wrapped.ciphertext);
+                }
+                None => out.extend_from_slice(&[SLOT_EMPTY; 1 + SLOT_BODY_LEN]),
+            }
+        }
+        out
+    }
+
+    /// Bytes every chunk is authenticated against: everything except the
+    /// slot table.
+    pub fn associated_data(&self) -> Vec<u8> {
+        self.encode_prefix()
+    }
+
+    /// Bytes a slot's wrapped key is authenticated against: the fixed header
+    /// fields followed by the slot's own parameters.
+    pub fn slot_associated_data(&self, slot: &KeySlot) -> Vec<u8> {
+        let mut out = self.encode_prefix();
+        slot.encode_params_into(&mut out);
+        out
+    }
+
+    /// Whether the payload starts with a metadata block.
+    pub fn has_metadata(&self) -> bool {
+        self.flags & FLAG_METADATA != 0
+    }
+
+    /// Whether the payload holds a directory archive instead of file contents.
+    pub fn has_archive(&self) -> bool {
+        self.flags & FLAG_ARCHIVE != 0
+    }
+
+    /// Indices of the slots currently in use.
+    pub fn active_slots(&self) -> Vec<usize> {
+        (0..self.slots.len()).filter(|&i| self.slots[i].is_some()).collect()
+    }
+
+    /// Parses the header that follows the streaming magic, which the caller
+    /// has already consumed, and validates every field.
+    pub fn read_after_magic<R: Read>(reader: &mut R) -> Result<Self, Box<dyn std::error::Error>> {
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(format!("Unsupported format version {}.", version).into());
+        }
+        let cipher = Cipher::from_id(read_u8(reader)?)?;
+        let flags = read_u8(reader)?;
+        if flags & !(FLAG_METADATA | FLAG_ARCHIVE) != 0 {
+            return Err(format!("Unsupported header flags {:#04x}.", flags).into());
+        }
+        let nonce_prefix = read_array(reader)?;
+        let chunk_size = read_chunk_size(reader)?;
+        let slot_count = read_u8(reader)? as usize;
+        if slot_count == 0 || slot_count > MAX_SLOTS {
+            return Err("Invalid key slot count in file header.".into());
+        }
+        let mut slots = Vec::with_capacity(slot_count);
+        for _ in 0..slot_count {
+            slots.push(Self::read_slot(reader)?);
+        }
+        Ok(Header { cipher, flags, nonce_prefix, chunk_size, slots })
+    }
+
+    fn read_slot<R: Read>(reader: &mut R) -> Result<Option<KeySlot>, Box<dyn std::error::Error>> {
+        let state = read_u8(reader)?;
+        if state == SLOT_EMPTY {
+            // Skip the rest of the zeroed slot.
+            read_array::<R, SLOT_BODY_LEN>(reader)?;
+            return Ok(None);
+        }
+        if state != SLOT_ACTIVE {
+            return Err(format!("Invalid key slot state {}.", state).into());
+        }
+        let flags = read_u8(reader)?;
+        if flags & !SLOT_FLAG_KEYFILE != 0 {
+            return Err(format!("Unsupported key slot flags {:#04x}.", flags).into());
+        }
+        let kdf = KdfParams::read(reader)?;
+        let salt = read_array(reader)?;
+        let wrapped = WrappedKey { nonce: read_array(reader)?, ciphertext: read_array(reader)? };
+        Ok(Some(KeySlot { flags, kdf, salt, wrapped }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Offsets into the header after the magic.
+    const FLAGS_AT: usize = 2;
+    const CHUNK_SIZE_AT: usize = 10;
+    const SLOT_COUNT_AT: usize = 14;
+    const SLOT_AT: usize = 15;
+    const SLOT_LEN: usize = 1 + SLOT_BODY_LEN;
+
+    fn slot(seed: u8, flags: u8) -> KeySlot {
+        KeySlot {
+            flags,
+            kdf: KdfParams { memory_kib: 8192 + seed as u32, iterations: 1 + seed as u32, lanes: 64 - seed as u32 },
+            salt: [seed; SALT_LEN],
+            wrapped: WrappedKey { nonce: [seed ^ 0x55; WRAP_NONCE_LEN], ciphertext: [seed ^ 0xaa; WRAPPED_KEY_LEN] },
+        }
+    }
+
+    /// A header whose slots alternate between active (with and without a
+    /// keyfile) and empty.
+    fn sample() -> Header {
+        let mut header = Header::new(Cipher::Aes256GcmSiv, [1, 2, 3, 4, 5, 6, 7], 65536);
+        header.flags = FLAG_METADATA | FLAG_ARCHIVE;
+        header.slots[0] = Some(slot(1, 0));
+        header.slots[3] = Some(slot(2, SLOT_FLAG_KEYFILE));
+        header.slots[7] = Some(slot(3, 0));
+        header
+    }
+
+    fn body(header: &Header) -> Vec<u8> {
+        let encoded = header.encode();
+        assert_eq!(&encoded[..STREAM_MAGIC.len()], STREAM_MAGIC);
+        encoded[STREAM_MAGIC.len()..].to_vec()
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Header, String> {
+        Header::read_after_magic(&mut &bytes[..]).map_err(|e| e.to_string())
+    }
+
+    /// Parses `bytes` after `edit`, expecting an error that contains `message`.
+    fn rejects(edit: impl FnOnce(&mut Vec<u8>), message: &str) {
+        let mut bytes = body(&sample());
+        edit(&mut bytes);
+        let error = parse(&bytes).expect_err("header was accepted");
+        assert!(error.contains(message), "{:?} does not mention {:?}", error, message);
+    }
+
+    fn put_u32(bytes: &mut [u8], at: usize, value: u32) {
+        bytes[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    #[test]
+    fn full_slot_table_round_trips() {
+        let header = sample();
+        let bytes = body(&header);
+        assert_eq!(bytes.len(), SLOT_AT + MAX_SLOTS * SLOT_LEN);
+
+        let parsed = parse(&bytes).unwrap();
+        assert_eq!(parsed.cipher, header.cipher);
+        assert_eq!(parsed.flags, header.flags);
+        assert_eq!(parsed.nonce_prefix, header.nonce_prefix);
+        assert_eq!(parsed.chunk_size, header.chunk_size);
+        assert_eq!(parsed.active_slots(), vec![0, 3, 7]);
+        for (parsed, original) in parsed.slots.iter().zip(&header.slots) {
+            match (parsed, original) {
+                (Some(parsed), Some(original)) => {
+                    assert_eq!(parsed.flags, original.flags);
+                    assert_eq!(parsed.kdf, original.kdf);
+                    assert_eq!(parsed.salt, original.salt);
+                    assert_eq!(parsed.wrapped.nonce, original.wrapped.nonce);
+                    assert_eq!(parsed.wrapped.ciphertext, original.wrapped.ciphertext);
+                }
+                (None, None) => {}
+                _ => panic!("slot state changed in the round trip"),
+            }
+        }
+        assert!(parsed.slots[3].as_ref().unwrap().requires_keyfile());
+        assert!(!parsed.slots[0].as_ref().unwrap().requires_keyfile());
+        assert_eq!(parsed.encode(), header.encode());
+    }
+
+    #[test]
+    fn kdf_bounds_are_inclusive() {
+        let mut header = sample();
+        header.slots[0].as_mut().unwrap().kdf = KdfParams { memory_kib: MIN_MEMORY_KIB, iterations: MIN_ITERATIONS, lanes: MIN_LANES };
+        header.slots[3].as_mut().unwrap().kdf = KdfParams { memory_kib: MAX_MEMORY_KIB, iterations: MAX_ITERATIONS, lanes: MAX_LANES };
+        let parsed = parse(&body(&header)).unwrap();
+        assert_eq!(parsed.slots[0].as_ref().unwrap().kdf, header.slots[0].as_ref().unwrap().kdf);
+        assert_eq!(parsed.slots[3].as_ref().unwrap().kdf, header.slots[3].as_ref().unwrap().kdf);
+    }
+
+    #[test]
+    fn rejects_bad_fixed_fields() {
+        rejects(|b| b[0] = VERSION + 1, "format version");
+        rejects(|b| b[1] = 3, "cipher");
+        rejects(|b| b[FLAGS_AT] = 0x04, "header flags");
+        rejects(|b| put_u32(b, CHUNK_SIZE_AT, 0), "chunk size");
+        rejects(|b| put_u32(b, CHUNK_SIZE_AT, MAX_CHUNK_SIZE as u32 + 1), "chunk size");
+        rejects(|b| b[SLOT_COUNT_AT] = 0, "slot count");
+        rejects(|b| b[SLOT_COUNT_AT] = MAX_SLOTS as u8 + 1, "slot count");
+    }
+
+    #[test]
+    fn rejects_bad_slots() {
+        // Slot 0 is active; its fields follow the state byte.
+        let flags = SLOT_AT + 1;
+        let kdf = flags + 1;
+        let (memory, iterations, lanes) = (kdf + 1, kdf + 5, kdf + 9);
+        rejects(|b| b[SLOT_AT] = 2, "slot state");
+        rejects(|b| b[SLOT_AT + SLOT_LEN] = 0xff, "slot state");
+        rejects(|b| b[flags] = 0x02, "slot flags");
+        rejects(|b| b[kdf] = 2, "key derivation function");
+        rejects(|b| put_u32(b, memory, MIN_MEMORY_KIB - 1), "memory cost");
+        rejects(|b| put_u32(b, memory, MAX_MEMORY_KIB + 1), "memory cost");
+        rejects(|b| put_u32(b, iterations, 0), "iteration count");
+        rejects(|b| put_u32(b, iterations, MAX_ITERATIONS + 1), "iteration count");
+        rejects(|b| put_u32(b, lanes, 0), "lane count");
+        rejects(|b| put_u32(b, lanes, MAX_LANES + 1), "lane count");
+    }
+
+    #[test]
+    fn both_ciphers_stream() {
+        use common::stream::{DecryptReader, EncryptWriter};
+        use std::io::Write;
+        let (key, nonce_prefix) = ([7; 32], [9; NONCE_PREFIX_LEN]);
+        let plaintext: Vec<u8> = (0..100).collect();
+        for cipher in [Cipher::Aes256Gcm, Cipher::Aes256GcmSiv] {
+            let mut writer = EncryptWriter::with_encryptor(Vec::new(), cipher.encryptor(&key, &nonce_prefix), 16, b"header".to_vec());
+            writer.write_all(&plaintext).unwrap();
+            let ciphertext = writer.finish().unwrap();
+            let decrypt = |cipher: Cipher| {
+                let mut reader = DecryptReader::with_decryptor(ciphertext.as_slice(), cipher.decryptor(&key, &nonce_prefix),
+                                                               16, b"header".to_vec());
+                let mut decrypted = Vec::new();
+                reader.read_to_end(&mut decrypted).map(|_| decrypted)
+            };
+            assert_eq!(decrypt(cipher).unwrap(), plaintext);
+            let other = if cipher == Cipher::Aes256Gcm { Cipher::Aes256GcmSiv } else { Cipher::Aes256Gcm };
+            assert!(decrypt(other).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let bytes = body(&sample());
+        for len in [0, SLOT_COUNT_AT, SLOT_AT + 1, bytes.len() - 1] {
+            assert!(parse(&bytes[..len]).is_err(), "accepted {} bytes", len);
+        }
+    }
+}
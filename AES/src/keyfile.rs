@@ -0,0 +1,41 @@
+use std::fs;
+use std::io::{self, BufReader};
+use clap::Arg;
+use sha2::{Digest, Sha256};
+
+/// Length of the secret derived from a keyfile and fed to Argon2id.
+pub const KEYFILE_SECRET_LEN: usize = 32;
+
+/// The `--keyfile` option shared by the encrypt and decrypt subcommands.
+pub fn keyfile_arg() -> Arg {
+    Arg::new("keyfile")
+        .short('k')
+        .long("keyfile")
+        .num_args(1)
+        .value_name("PATH")
+        .help("Keyfile required in addition to the password (e.g. the key.key written by chagen)")
+}
+
+/// The `--new-keyfile` option of `add-slot` and `rekey`, protecting the new slot.
+pub fn new_keyfile_arg() -> Arg {
+    Arg::new("new-keyfile")
+        .long("new-keyfile")
+        .num_args(1)
+        .value_name("PATH")
+        .help("Keyfile required in addition to the new password")
+}
+
+/// Reads a keyfile of any size and condenses it into a fixed-length secret.
+/// The file is hashed in a streaming fashion, so multi-gigabyte pads from
+/// `chagen1` can be used without loading them into memory.
+pub fn read_keyfile(path: &str) -> Result<[u8; KEYFILE_SECRET_LEN], Box<dyn std::error::Error>> {
+    let file = fs::File::open(path)
+        .map_err(|e| format!("Failed to open keyfile {}: {}", path, e))?;
+    let mut hasher = Sha256::new();
+    let length = io::copy(&mut BufReader::new(file), &mut hasher)
+        .map_err(|e| format!("Failed to read keyfile {}: {}", path, e))?;
+    if length == 0 {
+        return Err(format!("Keyfile {} is empty.", path).into());
+    }
+    Ok(hasher.finalize().into())
+}
@@ -0,0 +1,91 @@
+use std::io;
+use aes_gcm::aead::{Aead, Payload, generic_array::GenericArray};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use argon2::{Argon2, Params, Algorithm, Version};
+use rand::RngCore;
+use crate::header::{Header, KdfParams, KeySlot, WrappedKey, DATA_KEY_LEN, SALT_LEN, SLOT_FLAG_KEYFILE, WRAP_NONCE_LEN, WRAPPED_KEY_LEN};
+use crate::keyfile::KEYFILE_SECRET_LEN;
+
+/// What the user supplied to unlock (or to protect) a key slot.
+pub struct Credentials<'a> {
+    pub password: &'a str,
+    pub keyfile: Option<&'a [u8; KEYFILE_SECRET_LEN]>,
+}
+
+/// Derives a 32-byte key from the password and salt with Argon2id. When a
+/// keyfile is used, its secret is passed to Argon2id as the secret input, so
+/// both factors are needed to reproduce the key.
+pub fn derive_key(password: &str, salt: &[u8], kdf: &KdfParams, keyfile: Option<&[u8; KEYFILE_SECRET_LEN]>) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.lanes, Some(32))
+        .map_err(|e| io::Error::other(format!("Argon2 parameter error: {}", e)))?;
+    let argon2 = match keyfile {
+        Some(secret) => Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params)
+            .map_err(|e| io::Error::other(format!("Argon2 parameter error: {}", e)))?,
+        None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+    };
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| io::Error::other(format!("Argon2 hash error: {}", e)))?;
+    Ok(key)
+}
+
+/// Generates a fresh random data-encryption key.
+pub fn generate_data_key() -> [u8; DATA_KEY_LEN] {
+    let mut key = [0u8; DATA_KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Creates a key slot that wraps `data_key` under a key derived from the
+/// credentials with a fresh salt and the default Argon2id parameters.
+pub fn seal_slot(header: &Header, data_key: &[u8; DATA_KEY_LEN], credentials: &Credentials) -> Result<KeySlot, Box<dyn std::error::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut nonce = [0u8; WRAP_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let flags = if credentials.keyfile.is_some() { SLOT_FLAG_KEYFILE } else { 0 };
+    let mut slot = KeySlot { flags, kdf: KdfParams::DEFAULT, salt, wrapped: WrappedKey { nonce, ciphertext: [0u8; WRAPPED_KEY_LEN] } };
+
+    let wrapping_key = derive_key(credentials.password, &slot.salt, &slot.kdf, credentials.keyfile)?;
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(&wrapping_key));
+    let aad = header.slot_associated_data(&slot);
+    let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), Payload { msg: data_key, aad: &aad })
+        .map_err(|_| "Failed to wrap the data key")?;
+    slot.wrapped.ciphertext.copy_from_slice(&ciphertext);
+    Ok(slot)
+}
+
+/// Finds the key slot opened by the credentials and returns its index
+/// together with the key that encrypts the payload.
+///
+/// Slots are only tried if their keyfile requirement matches what was
+/// supplied.
+pub fn unlock(header: &Header, credentials: &Credentials) -> Result<(usize, [u8; 32]), Box<dyn std::error::Error>> {
+    let with_keyfile = credentials.keyfile.is_some();
+    let candidates: Vec<usize> = header.active_slots().into_iter()
+        .filter(|&i| header.slots[i].as_ref().is_some_and(|s| s.requires_keyfile() == with_keyfile))
+        .collect();
+    if candidates.is_empty() {
+        return Err(if with_keyfile {
+            "This file was not encrypted with a keyfile; omit --keyfile."
+        } else {
+            "This file was encrypted with a keyfile; pass it with --keyfile."
+        }.into());
+    }
+
+    for index in candidates {
+        let slot = header.slots[index].as_ref().expect("candidate slots are active");
+        let key = derive_key(credentials.password, &slot.salt, &slot.kdf, credentials.keyfile)?;
+        let wrapped = &slot.wrapped;
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        let aad = header.slot_associated_data(slot);
+        let payload = Payload { msg: &wrapped.ciphertext, aad: &aad };
+        if let Ok(data_key) = cipher.decrypt(GenericArray::from_slice(&wrapped.nonce), payload) {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&data_key);
+            return Ok((index, key));
+        }
+    }
+    Err("No key slot matches the given password or keyfile.".into())
+}
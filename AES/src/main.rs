@@ -1,39 +1,393 @@
+mod header;
+mod keyfile;
+mod keyslot;
+mod password;
+mod strength;
+
 use std::fs;
-use std::io::{Read, Write};
-use clap::{Arg, Command};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use clap::{Arg, ArgAction, Command};
 use rand::RngCore;
 use aes_gcm::aead::{Aead, generic_array::GenericArray};
 use aes_gcm::{Aes256Gcm, KeyInit};
-use argon2::{Argon2, Params, Algorithm, Version};
+use password::{password_args, read_password, NEW_PASSWORD, PASSWORD};
+use header::{Cipher, Header, KdfParams, FLAG_ARCHIVE, FLAG_METADATA, LEGACY_MAGIC, MAX_SLOTS, NONCE_PREFIX_LEN, SALT_LEN, STREAM_MAGIC};
+use common::{STDIO, archive, create_output, open_input};
+use common::inplace::{refuse_same_file, same_contents, PendingOutput, TempFile};
+use keyfile::{keyfile_arg, new_keyfile_arg, read_keyfile};
+use keyslot::{derive_key, generate_data_key, seal_slot, unlock, Credentials};
+use common::metadata::Metadata;
+use common::stream::{CHUNK_SIZE, DecryptReader, EncryptWriter};
+use strength::strength_args;
+use common::verify::{self, Verdict};
+
+/// Encrypts `input_path` into the streaming format: the header (see `header::Header`),
+/// then the encrypted chunks. The payload is encrypted with a random data key,
+/// which is wrapped into the first key slot under the given credentials. The
+/// file's name, size, mode and mtime are encrypted ahead of its contents. A
+/// directory is encrypted as an archive of the whole tree below it. Standard
+/// input has no metadata to store. Returns the data key, for verification.
+fn encrypt_file(input_path: &str, output_path: &str, cipher: Cipher, label: Option<&str>, credentials: &Credentials) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let is_dir = input_path != STDIO && Path::new(input_path).is_dir();
+    let metadata = if input_path == STDIO {
+        if label.is_some() {
+            return Err("--label cannot be used when encrypting standard input.".into());
+        }
+        None
+    } else {
+        Some(Metadata::from_file(input_path, label)?)
+    };
+    refuse_same_file(input_path, output_path)?;
+
+    // Generate a random nonce prefix and data key.
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_prefix);
+    let mut header = Header::new(cipher, nonce_prefix, CHUNK_SIZE as u32);
+    if metadata.is_some() {
+        header.flags |= FLAG_METADATA;
+    }
+    if is_dir {
+        header.flags |= FLAG_ARCHIVE;
+    }
+    let data_key = generate_data_key();
+    header.slots[0] = Some(seal_slot(&header, &data_key, credentials)?);
+
+    let mut out_file = BufWriter::new(create_output(output_path)?);
+    out_file.write_all(&header.encode())?;
+
+    // Encrypt chunk by chunk so memory use does not depend on the file size.
+    let mut writer = EncryptWriter::with_encryptor(out_file, header.cipher.encryptor(&data_key, &header.nonce_prefix),
+                                                   CHUNK_SIZE, header.associated_data());
+    if let Some(metadata) = &metadata {
+        writer.write_all(&metadata.encode()?)?;
+    }
+    if is_dir {
+        archive::pack(Path::new(input_path), &mut writer)?;
+    } else {
+        io::copy(&mut BufReader::new(open_input(input_path)?), &mut writer)?;
+    }
+    writer.finish()?;
+    Ok(data_key)
+}
+
+/// Checks that the streaming-format file at `encrypted_path` decrypts with
+/// `data_key` to exactly the contents of `original_path`.
+fn verify_encrypted(encrypted_path: &Path, data_key: &[u8; 32], original_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(fs::File::open(encrypted_path)?);
+    let mut magic = [0u8; 9];
+    file.read_exact(&mut magic)?;
+    let header = Header::read_after_magic(&mut file)?;
+    let mut reader = DecryptReader::with_decryptor(file, header.cipher.decryptor(data_key, &header.nonce_prefix),
+                                                   header.chunk_size as usize, header.associated_data());
+    if header.has_metadata() {
+        Metadata::read_from(&mut reader)?;
+    }
+    if &magic != STREAM_MAGIC || !same_contents(reader, BufReader::new(fs::File::open(original_path)?))? {
+        return Err("Verification of the encrypted file failed; the original was left unchanged.".into());
+    }
+    Ok(())
+}
+
+/// Replaces the regular file at `path` with its encryption. The ciphertext is
+/// written to a temporary file next to it, synced and decrypted again for
+/// comparison with the original, which is only then replaced by a rename.
+fn encrypt_in_place(path: &str, cipher: Cipher, label: Option<&str>, credentials: &Credentials) -> Result<(), Box<dyn std::error::Error>> {
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    let data_key = encrypt_file(path, temp_path, cipher, label, credentials)?;
+    verify_encrypted(temp.path(), &data_key, path)?;
+    temp.copy_permissions()?;
+    temp.commit()?;
+    Ok(())
+}
+
+/// Replaces the encrypted file at `path` with its plaintext. The plaintext is
+/// written to a temporary file next to it, and the original is only replaced
+/// once every chunk has been authenticated and the file has been synced.
+/// Directory archives cannot replace a file and are refused.
+fn decrypt_in_place(path: &str, action: MetadataAction, credentials: &Credentials) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(fs::File::open(path)?);
+    let mut magic = [0u8; 9];
+    file.read_exact(&mut magic)?;
+    if &magic == STREAM_MAGIC && Header::read_after_magic(&mut file)?.has_archive() {
+        return Err("This file holds a directory archive, which cannot be decrypted in place.".into());
+    }
+
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    decrypt_file(path, Some(temp_path), action, credentials)?;
+    if action != MetadataAction::Restore {
+        temp.copy_permissions()?;
+    }
+    temp.commit()?;
+    Ok(())
+}
+
+/// What `decrypt` does with the metadata stored in the file.
+#[derive(Clone, Copy, PartialEq)]
+enum MetadataAction {
+    /// Write the contents only.
+    Ignore,
+    /// Write the contents, then apply the stored mode and mtime.
+    Restore,
+    /// Print the metadata without writing the contents.
+    Show,
+}
+
+const NO_METADATA: &str = "This file has no stored metadata (it was encrypted from standard input or by an older version of this tool).";
+
+/// Decrypts a file in either the streaming or the legacy single-shot format.
+/// Without an output path, the file name stored in the metadata is used.
+fn decrypt_file(input_path: &str, output_path: Option<&str>, action: MetadataAction, credentials: &Credentials) -> Result<(), Box<dyn std::error::Error>> {
+    if output_path == Some(STDIO) && action == MetadataAction::Restore {
+        return Err("--restore-metadata cannot be used when writing to standard output.".into());
+    }
+    if let Some(output_path) = output_path {
+        refuse_same_file(input_path, output_path)?;
+    }
+    let mut file = BufReader::new(open_input(input_path)?);
+    let mut header = [0u8; 9];
+    file.read_exact(&mut header)?;
+    match &header {
+        STREAM_MAGIC => decrypt_stream(file, output_path, action, credentials),
+        LEGACY_MAGIC if credentials.keyfile.is_some() => Err("This file was not encrypted with a keyfile; omit --keyfile.".into()),
+        LEGACY_MAGIC => match (output_path, action) {
+            (Some(output_path), MetadataAction::Ignore) => decrypt_legacy(file, output_path, credentials.password),
+            _ => Err(NO_METADATA.into()),
+        },
+        _ if header.starts_with(b"CHAKEY") => Err("This file was encrypted with chakey; use chakey to decrypt it.".into()),
+        _ if header.starts_with(b"CHAPAS") => Err("This file was encrypted with chapas; use chapas to decrypt it.".into()),
+        _ => Err("Invalid file header. This file may not be encrypted with this tool.".into()),
+    }
+}
+
+/// Decrypts the streaming format chunk by chunk. The output file only takes
+/// its place once every chunk has authenticated, so a failure leaves an
+/// existing file untouched; on standard output, only authenticated chunks
+/// have been written when the error is reported.
+fn decrypt_stream<R: Read>(mut file: R, output_path: Option<&str>, action: MetadataAction, credentials: &Credentials) -> Result<(), Box<dyn std::error::Error>> {
+    // The Argon2id parameters come from the file, not from the current defaults.
+    let header = Header::read_after_magic(&mut file)?;
+    if !header.has_metadata() && (action != MetadataAction::Ignore || output_path.is_none()) {
+        return Err(NO_METADATA.into());
+    }
+    let (_, key) = unlock(&header, credentials)?;
+
+    // The payload cipher is recorded in the header, so it never has to be specified.
+    let mut reader = DecryptReader::with_decryptor(file, header.cipher.decryptor(&key, &header.nonce_prefix),
+                                                   header.chunk_size as usize, header.associated_data());
+    let metadata = if header.has_metadata() { Some(Metadata::read_from(&mut reader)?) } else { None };
+    if action == MetadataAction::Show {
+        // Only the chunks holding the metadata have been authenticated at this point.
+        println!("{}", metadata.expect("checked above"));
+        if header.has_archive() {
+            println!("Contents: directory archive");
+        }
+        return Ok(());
+    }
+
+    let named_from_metadata = output_path.is_none();
+    let output_path = match (output_path, &metadata) {
+        (Some(path), _) => path,
+        (None, Some(metadata)) => metadata.safe_name("--output")?,
+        (None, None) => unreachable!("checked above"),
+    };
+    if header.has_archive() && output_path == STDIO {
+        return Err("This file holds a directory archive, which cannot be written to standard output.".into());
+    }
+    if header.has_archive() {
+        // The tree is extracted into a new directory, which is removed again on failure.
+        archive::unpack(&mut reader, Path::new(output_path))?;
+    } else {
+        // A name taken from the file must not replace an existing file.
+        let mut out_file = PendingOutput::create(output_path, named_from_metadata)?;
+        io::copy(&mut reader, &mut out_file).map_err(|e| e.to_string())?;
+        out_file.commit()?;
+    }
+
+    if let (MetadataAction::Restore, Some(metadata)) = (action, &metadata) {
+        metadata.apply(output_path)?;
+    }
+    if named_from_metadata {
+        println!("Decrypted to {}.", output_path);
+    }
+    Ok(())
+}
+
+/// Authenticates every chunk of the file at `path` without writing any
+/// plaintext. A slot that cannot be unlocked means a wrong password or
+/// keyfile; a chunk that fails afterwards means the file is damaged. Legacy
+/// files cannot tell the two apart.
+fn verify_file(path: &Path, credentials: &Credentials) -> Verdict {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return Verdict::Failed(e.to_string()),
+    };
+    let mut magic = [0u8; 9];
+    if file.read_exact(&mut magic).is_err() {
+        return Verdict::Skipped("not encrypted with this tool".into());
+    }
+    match &magic {
+        STREAM_MAGIC => {
+            let header = match Header::read_after_magic(&mut file) {
+                Ok(header) => header,
+                Err(e) => return Verdict::Corrupt(e.to_string()),
+            };
+            let (_, key) = match unlock(&header, credentials) {
+                Ok(unlocked) => unlocked,
+                Err(e) => return Verdict::WrongKey(e.to_string()),
+            };
+            let mut reader = DecryptReader::with_decryptor(file, header.cipher.decryptor(&key, &header.nonce_prefix),
+                                                           header.chunk_size as usize, header.associated_data());
+            match io::copy(&mut reader, &mut io::sink()) {
+                Ok(_) => Verdict::Ok,
+                Err(e) => Verdict::Corrupt(e.to_string()),
+            }
+        }
+        LEGACY_MAGIC if credentials.keyfile.is_some() => Verdict::WrongKey("This file was not encrypted with a keyfile; omit --keyfile.".into()),
+        LEGACY_MAGIC => match legacy_plaintext(file, credentials.password) {
+            Ok(_) => Verdict::Ok,
+            Err(e) => Verdict::Failed(e.to_string()),
+        },
+        _ => Verdict::Skipped("not encrypted with this tool".into()),
+    }
+}
+
+/// Reads the header of a file whose payload key is held in key slots.
+fn read_slotted_header(path: &str) -> Result<Header, Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(fs::File::open(path)?);
+    let mut magic = [0u8; 9];
+    file.read_exact(&mut magic)?;
+    if &magic != STREAM_MAGIC {
+        return Err("Invalid file header. Key slots are only supported by the streaming format.".into());
+    }
+    Header::read_after_magic(&mut file)
+}
+
+/// Overwrites the header at the start of `path` and syncs it to disk. The
+/// slot table has a fixed size, so the payload that follows is left untouched.
+fn rewrite_header(path: &str, header: &Header) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(&header.encode())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Wraps the data key of `path` into a free key slot protected by `new_credentials`.
+/// Returns the index of the new slot.
+fn add_slot(path: &str, credentials: &Credentials, new_credentials: &Credentials) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut header = read_slotted_header(path)?;
+    let (_, data_key) = unlock(&header, credentials)?;
+    let index = header.slots.iter().position(Option::is_none)
+        .ok_or("All key slots are in use; remove one first.")?;
+    header.slots[index] = Some(seal_slot(&header, &data_key, new_credentials)?);
+    rewrite_header(path, &header)?;
+    Ok(index)
+}
+
+/// Replaces the key slot opened by `credentials` with one protected by
+/// `new_credentials`, using a fresh salt. Only the header is rewritten.
+///
+/// The new slot is written to a free slot and synced before the old one is
+/// erased, so a crash at any point leaves a header that opens with either the
+/// old or the new password. With every slot in use, nothing is changed.
+fn rekey(path: &str, credentials: &Credentials, new_credentials: &Credentials) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut header = read_slotted_header(path)?;
+    let (old_index, data_key) = unlock(&header, credentials)?;
+    let index = header.slots.iter().position(Option::is_none)
+        .ok_or("All key slots are in use, so the password cannot be changed safely; remove a slot first.")?;
+    header.slots[index] = Some(seal_slot(&header, &data_key, new_credentials)?);
+    rewrite_header(path, &header)?;
+    header.slots[old_index] = None;
+    rewrite_header(path, &header)?;
+    Ok(index)
+}
+
+/// Erases key slot `index` of `path` after checking that the credentials open
+/// the file. The last remaining slot cannot be removed.
+fn remove_slot(path: &str, index: usize, credentials: &Credentials) -> Result<(), Box<dyn std::error::Error>> {
+    let mut header = read_slotted_header(path)?;
+    unlock(&header, credentials)?;
+    if header.slots.get(index).is_none_or(Option::is_none) {
+        return Err(format!("Key slot {} is not in use.", index).into());
+    }
+    if header.active_slots().len() == 1 {
+        return Err("Refusing to remove the last key slot; the file could no longer be decrypted.".into());
+    }
+    header.slots[index] = None;
+    rewrite_header(path, &header)
+}
+
+/// Decrypts and authenticates the legacy format, which holds the whole file
+/// in a single AES-GCM message.
+fn legacy_plaintext<R: Read>(mut file: R, password: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // Read salt and nonce from the file.
+    let mut salt = [0u8; SALT_LEN];
+    file.read_exact(&mut salt)?;
+    let mut nonce = [0u8; 12];
+    file.read_exact(&mut nonce)?;
+    let mut ciphertext = Vec::new();
+    file.read_to_end(&mut ciphertext)?;
+
+    let key = derive_key(password, &salt, &KdfParams::DEFAULT, None)?;
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+
+    // Attempt decryption. If authentication fails, an error will be returned.
+    let plaintext = cipher.decrypt(GenericArray::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| "Decryption failed. Incorrect password or data is corrupted.")?;
+    Ok(plaintext)
+}
+
+/// Decrypts a file in the legacy format to `output_path`.
+fn decrypt_legacy<R: Read>(file: R, output_path: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let plaintext = legacy_plaintext(file, password)?;
+    let mut out_file = create_output(output_path)?;
+    out_file.write_all(&plaintext)?;
+    out_file.flush()?;
+    Ok(())
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("CHAAES")
         .version("1.0")
         .about("Encrypts and decrypts files securely using AES-256-GCM and Argon2id")
         .subcommand_required(true)
-        .subcommand(
-            Command::new("encrypt")
+        .subcommand(password_args(
+            strength_args(Command::new("encrypt")
                 .about("Encrypts a file")
                 .arg(Arg::new("input")
                      .short('i')
                      .long("input")
                      .required(true)
                      .num_args(1)
-                     .help("Path to the input file"))
+                     .help("Path to the input file or directory, or - for standard input"))
                 .arg(Arg::new("output")
                      .short('o')
                      .long("output")
-                     .required(true)
+                     .required_unless_present("in-place")
                      .num_args(1)
-                     .help("Path to the output (encrypted) file"))
-                .arg(Arg::new("password")
-                     .short('p')
-                     .long("password")
-                     .required(true)
+                     .help("Path to the output (encrypted) file, or - for standard output"))
+                .arg(Arg::new("in-place")
+                     .long("in-place")
+                     .action(ArgAction::SetTrue)
+                     .conflicts_with("output")
+                     .help("Replace the input file with its encryption, atomically and only once the result has been verified"))
+                .arg(Arg::new("cipher")
+                     .short('c')
+                     .long("cipher")
                      .num_args(1)
-                     .help("Password for encryption"))
-        )
-        .subcommand(
+                     .value_parser(["aes-gcm", "aes-gcm-siv"])
+                     .default_value("aes-gcm")
+                     .help("Payload cipher; aes-gcm-siv stays secure even if a nonce is ever repeated"))
+                .arg(Arg::new("label")
+                     .short('l')
+                     .long("label")
+                     .num_args(1)
+                     .help("Free-form text stored, encrypted, with the file's name, mode and mtime"))
+                .arg(keyfile_arg())),
+            &PASSWORD, "encryption"))
+        .subcommand(password_args(
             Command::new("decrypt")
                 .about("Decrypts a file")
                 .arg(Arg::new("input")
@@ -41,101 +395,164 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                      .long("input")
                      .required(true)
                      .num_args(1)
-                     .help("Path to the input (encrypted) file"))
+                     .help("Path to the input (encrypted) file, or - for standard input"))
                 .arg(Arg::new("output")
                      .short('o')
                      .long("output")
+                     .required_unless_present_any(["restore-metadata", "show-metadata", "in-place"])
+                     .num_args(1)
+                     .help("Path to the output (decrypted) file or - for standard output, or the directory to create for an archive; with --restore-metadata defaults to the stored name"))
+                .arg(Arg::new("restore-metadata")
+                     .long("restore-metadata")
+                     .action(ArgAction::SetTrue)
+                     .help("Restore the stored permissions and modification time"))
+                .arg(Arg::new("show-metadata")
+                     .long("show-metadata")
+                     .action(ArgAction::SetTrue)
+                     .conflicts_with_all(["output", "restore-metadata"])
+                     .help("Print the stored name, size, mode, mtime and label without writing the contents"))
+                .arg(Arg::new("in-place")
+                     .long("in-place")
+                     .action(ArgAction::SetTrue)
+                     .conflicts_with_all(["output", "show-metadata"])
+                     .help("Replace the input file with its plaintext, atomically and only once it has been fully authenticated"))
+                .arg(keyfile_arg()),
+            &PASSWORD, "decryption"))
+        .subcommand(password_args(
+            Command::new("verify")
+                .about("Checks that encrypted files authenticate, without writing any plaintext")
+                .arg(Arg::new("input")
+                     .short('i')
+                     .long("input")
                      .required(true)
                      .num_args(1)
-                     .help("Path to the output (decrypted) file"))
-                .arg(Arg::new("password")
-                     .short('p')
-                     .long("password")
+                     .help("Path to the encrypted file, or a directory to check every file in it recursively"))
+                .arg(keyfile_arg()),
+            &PASSWORD, "decryption"))
+        .subcommand(password_args(password_args(
+            strength_args(Command::new("add-slot")
+                .about("Adds a password to an encrypted file by rewriting its header")
+                .arg(Arg::new("input")
+                     .short('i')
+                     .long("input")
+                     .required(true)
+                     .num_args(1)
+                     .help("Path to the encrypted file"))
+                .arg(keyfile_arg())
+                .arg(new_keyfile_arg())),
+            &PASSWORD, "an existing key slot"),
+            &NEW_PASSWORD, "the new key slot"))
+        .subcommand(password_args(password_args(
+            strength_args(Command::new("rekey")
+                .about("Changes a password of an encrypted file without re-encrypting its contents")
+                .arg(Arg::new("input")
+                     .short('i')
+                     .long("input")
+                     .required(true)
+                     .num_args(1)
+                     .help("Path to the encrypted file"))
+                .arg(keyfile_arg())
+                .arg(new_keyfile_arg())),
+            &PASSWORD, "the current password"),
+            &NEW_PASSWORD, "the replacement password"))
+        .subcommand(password_args(
+            Command::new("remove-slot")
+                .about("Removes a password from an encrypted file by rewriting its header")
+                .arg(Arg::new("input")
+                     .short('i')
+                     .long("input")
+                     .required(true)
+                     .num_args(1)
+                     .help("Path to the encrypted file"))
+                .arg(Arg::new("slot")
+                     .short('s')
+                     .long("slot")
                      .required(true)
                      .num_args(1)
-                     .help("Password for decryption"))
-        )
+                     .value_parser(clap::value_parser!(u8).range(0..MAX_SLOTS as i64))
+                     .help("Index of the key slot to remove"))
+                .arg(keyfile_arg()),
+            &PASSWORD, "any remaining key slot"))
         .get_matches();
 
     match matches.subcommand() {
         Some(("encrypt", sub_m)) => {
             let input_path = sub_m.get_one::<String>("input").unwrap();
-            let output_path = sub_m.get_one::<String>("output").unwrap();
-            let password = sub_m.get_one::<String>("password").unwrap();
-
-            // Read the entire input file into memory.
-            let plaintext = fs::read(input_path)?;
-
-            // Generate random salt and nonce.
-            let mut salt = [0u8; 16];
-            rand::thread_rng().fill_bytes(&mut salt);
-            let mut nonce = [0u8; 12];
-            rand::thread_rng().fill_bytes(&mut nonce);
-
-            // Configure Argon2id parameters.
-            let params = Params::new(65536, 3, 4, Some(32))
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Argon2 parameter error: {}", e)))?;
-            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-
-            // Derive a 32-byte key from the password and salt.
-            let mut key = [0u8; 32];
-            argon2.hash_password_into(password.as_bytes(), &salt, &mut key)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Argon2 hash error: {}", e)))?;
-            let key = GenericArray::clone_from_slice(&key);
-
-            // Encrypt using AES-256-GCM (provides confidentiality and integrity).
-            let cipher = Aes256Gcm::new(&key);
-            let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), plaintext.as_ref())
-                .map_err(|_| "Encryption failed")?;
-
-            // Write output file: magic header, salt, nonce, then ciphertext.
-            let mut out_file = fs::File::create(output_path)?;
-            out_file.write_all(b"SECUREENC")?;  // 9-byte magic header
-            out_file.write_all(&salt)?;
-            out_file.write_all(&nonce)?;
-            out_file.write_all(&ciphertext)?;
+            let output_path = sub_m.get_one::<String>("output");
+            let cipher = match sub_m.get_one::<String>("cipher").unwrap().as_str() {
+                "aes-gcm-siv" => Cipher::Aes256GcmSiv,
+                _ => Cipher::Aes256Gcm,
+            };
+            let label = sub_m.get_one::<String>("label").map(String::as_str);
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, true)?;
+            strength::policy(sub_m).check(&password)?;
+            let credentials = Credentials { password: &password, keyfile: keyfile.as_ref() };
+            match output_path {
+                Some(output_path) => { encrypt_file(input_path, output_path, cipher, label, &credentials)?; }
+                None => encrypt_in_place(input_path, cipher, label, &credentials)?,
+            }
         },
         Some(("decrypt", sub_m)) => {
             let input_path = sub_m.get_one::<String>("input").unwrap();
-            let output_path = sub_m.get_one::<String>("output").unwrap();
-            let password = sub_m.get_one::<String>("password").unwrap();
-
-            // Open the encrypted file.
-            let mut file = fs::File::open(input_path)?;
-            let mut header = [0u8; 9];
-            file.read_exact(&mut header)?;
-            if &header != b"SECUREENC" {
-                return Err("Invalid file header. This file may not be encrypted with this tool.".into());
+            let output_path = sub_m.get_one::<String>("output").map(String::as_str);
+            let action = if sub_m.get_flag("show-metadata") {
+                MetadataAction::Show
+            } else if sub_m.get_flag("restore-metadata") {
+                MetadataAction::Restore
+            } else {
+                MetadataAction::Ignore
+            };
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, false)?;
+            let credentials = Credentials { password: &password, keyfile: keyfile.as_ref() };
+            if sub_m.get_flag("in-place") {
+                decrypt_in_place(input_path, action, &credentials)?;
+            } else {
+                decrypt_file(input_path, output_path, action, &credentials)?;
             }
-
-            // Read salt and nonce from the file.
-            let mut salt = [0u8; 16];
-            file.read_exact(&mut salt)?;
-            let mut nonce = [0u8; 12];
-            file.read_exact(&mut nonce)?;
-            let mut ciphertext = Vec::new();
-            file.read_to_end(&mut ciphertext)?;
-
-            // Recreate Argon2id configuration to derive the same key.
-            let params = Params::new(65536, 3, 4, Some(32))
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Argon2 parameter error: {}", e)))?;
-            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-
-            let mut key = [0u8; 32];
-            argon2.hash_password_into(password.as_bytes(), &salt, &mut key)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Argon2 hash error: {}", e)))?;
-            let key = GenericArray::clone_from_slice(&key);
-            let cipher = Aes256Gcm::new(&key);
-
-            // Attempt decryption. If authentication fails, an error will be returned.
-            let plaintext = cipher.decrypt(GenericArray::from_slice(&nonce), ciphertext.as_ref())
-                .map_err(|_| "Decryption failed. Incorrect password or data is corrupted.")?;
-            fs::write(output_path, plaintext)?;
+        },
+        Some(("add-slot", sub_m)) => {
+            let input_path = sub_m.get_one::<String>("input").unwrap();
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let new_keyfile = sub_m.get_one::<String>("new-keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, false)?;
+            let new_password = read_password(sub_m, &NEW_PASSWORD, true)?;
+            strength::policy(sub_m).check(&new_password)?;
+            let index = add_slot(input_path,
+                                 &Credentials { password: &password, keyfile: keyfile.as_ref() },
+                                 &Credentials { password: &new_password, keyfile: new_keyfile.as_ref() })?;
+            println!("Added key slot {}.", index);
+        },
+        Some(("rekey", sub_m)) => {
+            let input_path = sub_m.get_one::<String>("input").unwrap();
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let new_keyfile = sub_m.get_one::<String>("new-keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, false)?;
+            let new_password = read_password(sub_m, &NEW_PASSWORD, true)?;
+            strength::policy(sub_m).check(&new_password)?;
+            let index = rekey(input_path,
+                              &Credentials { password: &password, keyfile: keyfile.as_ref() },
+                              &Credentials { password: &new_password, keyfile: new_keyfile.as_ref() })?;
+            println!("Password changed; the new password is in key slot {}.", index);
+        },
+        Some(("verify", sub_m)) => {
+            let input_path = sub_m.get_one::<String>("input").unwrap();
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, false)?;
+            let credentials = Credentials { password: &password, keyfile: keyfile.as_ref() };
+            verify::run(Path::new(input_path), |path| verify_file(path, &credentials))?;
+        },
+        Some(("remove-slot", sub_m)) => {
+            let input_path = sub_m.get_one::<String>("input").unwrap();
+            let index = *sub_m.get_one::<u8>("slot").unwrap() as usize;
+            let keyfile = sub_m.get_one::<String>("keyfile").map(|p| read_keyfile(p)).transpose()?;
+            let password = read_password(sub_m, &PASSWORD, false)?;
+            remove_slot(input_path, index, &Credentials { password: &password, keyfile: keyfile.as_ref() })?;
+            println!("Removed key slot {}.", index);
         },
         _ => unreachable!(),
     }
 
     Ok(())
 }
-
-
@@ -0,0 +1,100 @@
+use clap::{Arg, ArgGroup, ArgMatches, Command};
+use common::password;
+use rpassword::prompt_password;
+
+/// Names of the command-line options that supply one password. Subcommands
+/// that need a second password (e.g. `add-slot`) use a second set.
+pub struct PasswordSource {
+    pub password: &'static str,
+    pub env: &'static str,
+    pub file: &'static str,
+    pub fd: &'static str,
+    pub group: &'static str,
+    pub short: Option<char>,
+    pub prompt: &'static str,
+    pub confirm_prompt: &'static str,
+}
+
+/// The password that encrypts a file or unlocks one of its key slots.
+pub const PASSWORD: PasswordSource = PasswordSource {
+    password: "password",
+    env: "password-env",
+    file: "password-file",
+    fd: "password-fd",
+    group: "password-source",
+    short: Some('p'),
+    prompt: "Enter password: ",
+    confirm_prompt: "Re-enter password: ",
+};
+
+/// The password protecting a newly added or replaced key slot.
+pub const NEW_PASSWORD: PasswordSource = PasswordSource {
+    password: "new-password",
+    env: "new-password-env",
+    file: "new-password-file",
+    fd: "new-password-fd",
+    group: "new-password-source",
+    short: None,
+    prompt: "Enter new password: ",
+    confirm_prompt: "Re-enter new password: ",
+};
+
+/// Adds the mutually exclusive options for one password source to a subcommand.
+/// When none of them is given the password is prompted for on the terminal.
+pub fn password_args(cmd: Command, source: &PasswordSource, purpose: &str) -> Command {
+    let mut password = Arg::new(source.password)
+        .long(source.password)
+        .num_args(1)
+        .help(format!("Password for {} (visible in shell history and process listings; prefer the options below)", purpose));
+    if let Some(short) = source.short {
+        password = password.short(short);
+    }
+    cmd.arg(password)
+        .arg(Arg::new(source.env)
+            .long(source.env)
+            .num_args(1)
+            .value_name("NAME")
+            .help(format!("Read the password for {} from the environment variable NAME", purpose)))
+        .arg(Arg::new(source.file)
+            .long(source.file)
+            .num_args(1)
+            .value_name("PATH")
+            .help(format!("Read the password for {} from the first line of the file at PATH", purpose)))
+        .arg(Arg::new(source.fd)
+            .long(source.fd)
+            .num_args(1)
+            .value_name("N")
+            .value_parser(clap::value_parser!(i32))
+            .help(format!("Read the password for {} from the first line of open file descriptor N", purpose)))
+        .group(ArgGroup::new(source.group)
+            .args([source.password, source.env, source.file, source.fd]))
+}
+
+/// Resolves a password from whichever of `source`'s options was given on the
+/// command line. Without an explicit option the user is prompted on the
+/// terminal, twice when `confirm` is set (i.e. when setting a password).
+pub fn read_password(matches: &ArgMatches, source: &PasswordSource, confirm: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(password) = matches.get_one::<String>(source.password) {
+        return Ok(password.clone());
+    }
+    if let Some(name) = matches.get_one::<String>(source.env) {
+        return password::from_env(name);
+    }
+    if let Some(path) = matches.get_one::<String>(source.file) {
+        return password::from_file(path);
+    }
+    if let Some(&fd) = matches.get_one::<i32>(source.fd) {
+        return password::from_fd(fd);
+    }
+
+    let prompt = |text: &str| prompt_password(text)
+        .map_err(|e| format!("Failed to read password from the terminal: {}", e));
+    let password = prompt(source.prompt)?;
+    if confirm {
+        let again = prompt(source.confirm_prompt)?;
+        if password != again {
+            return Err("Passwords do not match.".into());
+        }
+    }
+    Ok(password)
+}
@@ -0,0 +1,24 @@
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use common::strength::{DEFAULT_MIN_SCORE, MAX_SCORE, Policy};
+
+/// Adds `--min-score` and `--allow-weak` to a subcommand that sets a password.
+pub fn strength_args(cmd: Command) -> Command {
+    cmd.arg(Arg::new("min-score")
+            .long("min-score")
+            .num_args(1)
+            .value_name("0-4")
+            .value_parser(clap::value_parser!(u8).range(0..=MAX_SCORE as i64))
+            .help("Lowest accepted password strength, from 0 (anything) to 4 (very strong); default 3"))
+        .arg(Arg::new("allow-weak")
+            .long("allow-weak")
+            .action(ArgAction::SetTrue)
+            .help("Accept a password that is common or below --min-score, with a warning"))
+}
+
+/// The policy selected by the options of `strength_args`.
+pub fn policy(matches: &ArgMatches) -> Policy {
+    Policy {
+        min_score: matches.get_one::<u8>("min-score").copied().unwrap_or(DEFAULT_MIN_SCORE),
+        allow_weak: matches.get_flag("allow-weak"),
+    }
+}
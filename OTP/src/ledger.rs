@@ -0,0 +1,357 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use crate::pad::{Layout, PAD_ID_LEN, Pad, Role, Stream, format_id};
+
+/// First line of a new ledger file.
+const LEDGER_HEADER: &str = "# otp ledger v1: <pad fingerprint>[:<A|B>] <start> <end> <enc|dec>, or <pad fingerprint> split <layout> <A|B>";
+
+/// Why a range of pad bytes was consumed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Use {
+    /// Encrypted a message sent from this side.
+    Encrypt,
+    /// Decrypted a message received from the other side.
+    Decrypt,
+}
+
+impl Use {
+    fn as_str(self) -> &'static str {
+        match self {
+            Use::Encrypt => "enc",
+            Use::Decrypt => "dec",
+        }
+    }
+}
+
+/// A consumed byte range `start..end` of one stream.
+pub struct Range {
+    pub stream: String,
+    pub start: u64,
+    pub end: u64,
+    pub kind: Use,
+}
+
+impl Range {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// How this side shares a pad with its peer.
+struct Split {
+    pad: String,
+    layout: Layout,
+    role: Role,
+}
+
+/// The record of which pad bytes have been used, for every pad, keyed by pad
+/// fingerprint. For a split pad, each party's share is tracked on its own and
+/// offsets count from the start of the share. Ranges are only ever added, and
+/// each is synced to disk before the pad bytes it covers are used, so a crash
+/// can waste pad material but never cause it to be used twice. The file stays
+/// locked while it is open, so concurrent runs cannot hand out the same range.
+pub struct Ledger {
+    path: PathBuf,
+    file: File,
+    ranges: Vec<Range>,
+    splits: Vec<Split>,
+}
+
+/// Whether `key` names a whole pad or a share of one.
+fn valid_stream(key: &str) -> bool {
+    let (pad, role) = key.split_once(':').map_or((key, None), |(pad, role)| (pad, Some(role)));
+    pad.len() == 2 * PAD_ID_LEN && pad.bytes().all(|b| b.is_ascii_hexdigit())
+        && role.is_none_or(|role| Role::parse(role).is_some())
+}
+
+impl Ledger {
+    /// Opens and locks the ledger at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)
+            .map_err(|e| format!("Cannot open ledger {}: {}", path.display(), e))?;
+        file.lock()?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        if text.is_empty() {
+            writeln!(file, "{}", LEDGER_HEADER)?;
+        }
+
+        let mut ranges = Vec::new();
+        let mut splits = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("Invalid entry on line {} of ledger {}.", number + 1, path.display());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [stream, start, end, kind] = fields[..] else {
+                return Err(invalid().into());
+            };
+            if !valid_stream(stream) {
+                return Err(invalid().into());
+            }
+            if start == "split" {
+                let (Some(layout), Some(role)) = (Layout::parse(end), Role::parse(kind)) else {
+                    return Err(invalid().into());
+                };
+                if stream.contains(':') {
+                    return Err(invalid().into());
+                }
+                // A split cannot be changed, so a second one must agree with the first.
+                if let Some(split) = splits.iter().find(|split: &&Split| split.pad == stream) {
+                    if (split.layout, split.role) != (layout, role) {
+                        return Err(format!("Line {} of ledger {} splits pad {} again, differently.",
+                                           number + 1, path.display(), stream).into());
+                    }
+                    continue;
+                }
+                splits.push(Split { pad: stream.to_string(), layout, role });
+                continue;
+            }
+            let kind = match kind {
+                "enc" => Use::Encrypt,
+                "dec" => Use::Decrypt,
+                _ => return Err(invalid().into()),
+            };
+            let (Ok(start), Ok(end)) = (start.parse(), end.parse()) else {
+                return Err(invalid().into());
+            };
+            if start > end {
+                return Err(invalid().into());
+            }
+            ranges.push(Range { stream: stream.to_string(), start, end, kind });
+        }
+        Ok(Ledger { path: path.to_path_buf(), file, ranges, splits })
+    }
+
+    /// How `pad` is split, and which side this is. Fails if the recorded
+    /// layout does not fit the pad.
+    pub fn split(&self, pad: &Pad) -> Result<Option<(Layout, Role)>, Box<dyn std::error::Error>> {
+        let id = format_id(pad.id());
+        let Some(split) = self.splits.iter().find(|split| split.pad == id) else {
+            return Ok(None);
+        };
+        pad.check_layout(split.layout).map_err(|e| format!("Ledger {}: {}", self.path.display(), e))?;
+        Ok(Some((split.layout, split.role)))
+    }
+
+    /// Whether any range of the pad, or of a share of it, has been consumed.
+    pub fn has_ranges(&self, pad: &[u8; PAD_ID_LEN]) -> bool {
+        let pad = format_id(pad);
+        self.ranges.iter().any(|range| range.stream.split(':').next() == Some(pad.as_str()))
+    }
+
+    /// Records that the pad is split with `layout` and this side is `role`.
+    pub fn set_split(&mut self, pad: &[u8; PAD_ID_LEN], layout: Layout, role: Role) -> Result<(), Box<dyn std::error::Error>> {
+        let pad = format_id(pad);
+        self.append(&format!("{} split {} {}", pad, layout, role))?;
+        self.splits.push(Split { pad, layout, role });
+        Ok(())
+    }
+
+    /// Ranges consumed from `stream`.
+    pub fn ranges<'a>(&'a self, stream: &Stream) -> impl Iterator<Item = &'a Range> {
+        let key = stream.key();
+        self.ranges.iter().filter(move |range| range.stream == key)
+    }
+
+    /// First offset after every consumed range of the stream.
+    pub fn next_free(&self, stream: &Stream) -> u64 {
+        self.ranges(stream).map(|range| range.end).max().unwrap_or(0)
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| format!("Cannot update ledger {}: {}", self.path.display(), e).into())
+    }
+
+    /// Records `start..end` of the stream as consumed and syncs the ledger.
+    pub fn record(&mut self, stream: &Stream, start: u64, end: u64, kind: Use) -> Result<(), Box<dyn std::error::Error>> {
+        let range = Range { stream: stream.key(), start, end, kind };
+        self.append(&format!("{} {} {} {}", range.stream, range.start, range.end, kind.as_str()))?;
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    /// Reserves `len` bytes of the stream for encrypting a message, from the
+    /// first offset no earlier range has touched, and returns that offset.
+    pub fn allocate(&mut self, stream: &Stream, len: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        let start = self.next_free(stream);
+        let size = stream.len();
+        let end = start.checked_add(len).filter(|&end| end <= size).ok_or_else(|| {
+            format!("{} is used up: {} bytes are needed, but only {} of its {} bytes remain unused.",
+                    stream, len, size.saturating_sub(start), size)
+        })?;
+        if len > 0 {
+            self.record(stream, start, end, Use::Encrypt)?;
+        }
+        Ok(start)
+    }
+
+    /// Whether `start..end` of the stream overlaps a range consumed for
+    /// encryption other than exactly that range, which means that both sides
+    /// have used the same pad bytes.
+    pub fn collides(&self, stream: &Stream, start: u64, end: u64) -> bool {
+        self.ranges(stream).any(|range| range.kind == Use::Encrypt && range.overlaps(start, end)
+                                      && (range.start, range.end) != (start, end))
+    }
+
+    /// Whether any consumed range of the stream overlaps `start..end`.
+    pub fn in_use(&self, stream: &Stream, start: u64, end: u64) -> bool {
+        self.ranges(stream).any(|range| range.overlaps(start, end))
+    }
+
+    /// Whether `start..end` of the stream is already recorded as decrypted.
+    pub fn has_decrypted(&self, stream: &Stream, start: u64, end: u64) -> bool {
+        self.ranges(stream).any(|range| range.kind == Use::Decrypt && range.start <= start && end <= range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::pad::tests::test_pad;
+
+    /// A ledger file holding `lines` after the usual header line.
+    fn ledger_with(dir: &TempDir, lines: &[&str]) -> PathBuf {
+        let path = dir.path().join("otp.ledger");
+        let mut text = format!("{}\n", LEDGER_HEADER);
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let (_file, pad) = test_pad(100);
+        let id = format_id(pad.id());
+        let lines = [
+            format!("{} 0 10", id),
+            format!("{} 0 10 enc extra", id),
+            format!("{} 0 10 use", id),
+            format!("{} ten 20 enc", id),
+            format!("{} -1 20 enc", id),
+            format!("{} 20 10 dec", id),
+            format!("{} 0 10 enc", &id[1..]),
+            format!("{}zz 0 10 enc", &id[2..]),
+            format!("{}:C 0 10 enc", id),
+            format!("{} split thirds A", id),
+            format!("{} split halves C", id),
+            format!("{} split interleave:0 A", id),
+            format!("{}:A split halves A", id),
+        ];
+        let dir = TempDir::new().unwrap();
+        for line in &lines {
+            let path = ledger_with(&dir, &["# comment", "", line]);
+            let error = Ledger::open(&path).err().unwrap_or_else(|| panic!("accepted {:?}", line)).to_string();
+            assert!(error.contains("line 4"), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn duplicate_lines() {
+        let (_file, pad) = test_pad(100);
+        let id = format_id(pad.id());
+        let dir = TempDir::new().unwrap();
+
+        let range = format!("{} 0 10 enc", id);
+        let path = ledger_with(&dir, &[&range, &range]);
+        let ledger = Ledger::open(&path).unwrap();
+        assert_eq!(ledger.ranges(&pad.stream(None)).count(), 2);
+        assert_eq!(ledger.next_free(&pad.stream(None)), 10);
+        drop(ledger);
+
+        let split = format!("{} split halves A", id);
+        let path = ledger_with(&dir, &[&split, &split]);
+        assert!(Ledger::open(&path).unwrap().split(&pad).unwrap() == Some((Layout::Halves, Role::A)));
+
+        let other = format!("{} split halves B", id);
+        let path = ledger_with(&dir, &[&split, &other]);
+        assert!(Ledger::open(&path).is_err());
+    }
+
+    #[test]
+    fn allocations_never_overlap_used_ranges() {
+        let (_file, pad) = test_pad(100);
+        let stream = pad.stream(None);
+        let id = format_id(pad.id());
+        let dir = TempDir::new().unwrap();
+        let path = ledger_with(&dir, &[&format!("{} 40 50 dec", id), &format!("{} 5 10 enc", id)]);
+
+        let mut ledger = Ledger::open(&path).unwrap();
+        let first = ledger.allocate(&stream, 20).unwrap();
+        assert_eq!(first, 50);
+        let second = ledger.allocate(&stream, 5).unwrap();
+        assert_eq!(second, 70);
+        assert_eq!(ledger.allocate(&stream, 0).unwrap(), 75);
+        assert_eq!(ledger.ranges(&stream).count(), 4);
+        drop(ledger);
+
+        // The allocations were synced to the file and survive reopening.
+        let mut ledger = Ledger::open(&path).unwrap();
+        assert_eq!(ledger.next_free(&stream), 75);
+        assert!(ledger.in_use(&stream, 69, 71));
+        assert!(!ledger.in_use(&stream, 10, 40));
+        assert_eq!(ledger.allocate(&stream, 25).unwrap(), 75);
+    }
+
+    #[test]
+    fn shares_are_allocated_separately() {
+        let (_file, pad) = test_pad(100);
+        let dir = TempDir::new().unwrap();
+        let mut ledger = Ledger::open(&ledger_with(&dir, &[])).unwrap();
+        ledger.set_split(pad.id(), Layout::Interleave(10), Role::A).unwrap();
+        let (a, b) = (pad.stream(Some((Layout::Interleave(10), Role::A))), pad.stream(Some((Layout::Interleave(10), Role::B))));
+        assert_eq!(ledger.allocate(&a, 30).unwrap(), 0);
+        assert_eq!(ledger.allocate(&b, 10).unwrap(), 0);
+        assert_eq!(ledger.allocate(&a, 10).unwrap(), 30);
+        assert!(ledger.has_ranges(pad.id()));
+        assert_eq!(ledger.next_free(&pad.stream(None)), 0);
+    }
+
+    #[test]
+    fn exhausted_pad_is_refused() {
+        let (_file, pad) = test_pad(100);
+        let stream = pad.stream(None);
+        let dir = TempDir::new().unwrap();
+        let mut ledger = Ledger::open(&ledger_with(&dir, &[])).unwrap();
+        assert_eq!(ledger.allocate(&stream, 60).unwrap(), 0);
+        let error = ledger.allocate(&stream, 41).err().unwrap().to_string();
+        assert!(error.contains("only 40 of its 100 bytes remain"), "{}", error);
+        assert!(ledger.allocate(&stream, u64::MAX).is_err());
+        assert_eq!(ledger.ranges(&stream).count(), 1);
+        assert_eq!(ledger.allocate(&stream, 40).unwrap(), 60);
+        assert!(ledger.allocate(&stream, 1).is_err());
+        assert_eq!(ledger.allocate(&stream, 0).unwrap(), 100);
+    }
+
+    #[test]
+    fn collisions_and_decrypted_ranges() {
+        let (_file, pad) = test_pad(100);
+        let stream = pad.stream(None);
+        let id = format_id(pad.id());
+        let dir = TempDir::new().unwrap();
+        let path = ledger_with(&dir, &[&format!("{} 10 20 enc", id), &format!("{} 30 40 dec", id)]);
+        let ledger = Ledger::open(&path).unwrap();
+
+        // The exact range this side encrypted is its own message, not a collision.
+        assert!(!ledger.collides(&stream, 10, 20));
+        assert!(ledger.collides(&stream, 15, 25));
+        assert!(ledger.collides(&stream, 5, 11));
+        assert!(!ledger.collides(&stream, 20, 30));
+        assert!(!ledger.collides(&stream, 30, 40));
+
+        assert!(ledger.has_decrypted(&stream, 30, 40));
+        assert!(ledger.has_decrypted(&stream, 32, 38));
+        assert!(!ledger.has_decrypted(&stream, 25, 35));
+        assert!(!ledger.has_decrypted(&stream, 10, 20));
+    }
+}
@@ -0,0 +1,124 @@
+/// Pad bytes consumed by one MAC key: 16 for the hash key, 16 for the mask.
+pub const KEY_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+/// The prime 2^127 - 1; polynomial arithmetic is done modulo it.
+const P: u128 = (1 << 127) - 1;
+/// Message bytes per polynomial coefficient. With the marker byte a block
+/// stays below 2^121, so every coefficient is reduced.
+const BLOCK_LEN: usize = 15;
+
+/// A Wegman-Carter one-time MAC: the message is hashed by evaluating it as a
+/// polynomial at a secret point `r`, and the result is masked with a secret
+/// `s`. Both come from pad bytes used only once, so a forger who has seen the
+/// tag succeeds with probability at most about (message length / 15) / 2^127,
+/// whatever their computing power.
+pub struct Mac {
+    r: u128,
+    s: u128,
+    h: u128,
+    block: [u8; BLOCK_LEN],
+    filled: usize,
+}
+
+/// Reduces `x` modulo P, given `x` < 2^128.
+fn reduce(x: u128) -> u128 {
+    let x = (x & P) + (x >> 127);
+    if x >= P { x - P } else { x }
+}
+
+/// Multiplies `a` and `b` modulo P, given both are below P.
+fn mul_mod(a: u128, b: u128) -> u128 {
+    let (a0, a1) = (a as u64 as u128, a >> 64);
+    let (b0, b1) = (b as u64 as u128, b >> 64);
+    // Both halves' cross products stay below 2^127, so their sum fits.
+    let mid = a0 * b1 + a1 * b0;
+    let (lo, carry) = (a0 * b0).overflowing_add(mid << 64);
+    let hi = a1 * b1 + (mid >> 64) + carry as u128;
+    // 2^128 = 2 mod P, and hi < 2^126, so this sum cannot overflow.
+    reduce((lo & P) + (lo >> 127) + (hi << 1))
+}
+
+impl Mac {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let r = u128::from_le_bytes(key[..16].try_into().unwrap()) & P;
+        let s = u128::from_le_bytes(key[16..].try_into().unwrap());
+        Mac { r: reduce(r), s, h: 0, block: [0u8; BLOCK_LEN], filled: 0 }
+    }
+
+    /// Adds a block of `len` bytes, marked by a 1 byte after its end so that
+    /// messages of different lengths never hash alike.
+    fn absorb(&mut self, len: usize) {
+        let mut bytes = [0u8; 16];
+        bytes[..len].copy_from_slice(&self.block[..len]);
+        bytes[len] = 1;
+        let c = u128::from_le_bytes(bytes);
+        self.h = mul_mod(reduce(self.h + c), self.r);
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (BLOCK_LEN - self.filled).min(data.len());
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == BLOCK_LEN {
+                self.absorb(BLOCK_LEN);
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; TAG_LEN] {
+        if self.filled > 0 {
+            self.absorb(self.filled);
+        }
+        self.h.wrapping_add(self.s).to_le_bytes()
+    }
+
+    /// Whether the message hashes to `tag`, compared in constant time.
+    pub fn verify(self, tag: &[u8; TAG_LEN]) -> bool {
+        self.finalize().iter().zip(tag).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Multiplication modulo P by doubling and adding, one bit of `b` at a
+    /// time; slow, but every intermediate value stays below 2^128.
+    fn mul_mod_reference(a: u128, b: u128) -> u128 {
+        let mut product = 0;
+        for bit in (0..127).rev() {
+            product = reduce(product << 1);
+            if b >> bit & 1 == 1 {
+                product = reduce(product + a);
+            }
+        }
+        product
+    }
+
+    #[test]
+    fn mul_mod_matches_reference() {
+        let mut values = vec![0, 1, 2, P - 1, P - 2, 1 << 64, (1 << 64) - 1, 1 << 126, (1 << 126) - 1, P >> 1];
+        // xorshift128+, so the test is the same on every run.
+        let (mut x, mut y) = (0x9e37_79b9_7f4a_7c15u64, 0xbf58_476d_1ce4_e5b9u64);
+        for _ in 0..200 {
+            let mut next = || {
+                let (mut s1, s0) = (x, y);
+                x = s0;
+                s1 ^= s1 << 23;
+                y = s1 ^ s0 ^ (s1 >> 17) ^ (s0 >> 26);
+                y.wrapping_add(s0)
+            };
+            let value = (next() as u128) << 64 | next() as u128;
+            values.push(reduce(value & P));
+        }
+        for &a in &values {
+            for &b in &values {
+                assert_eq!(mul_mod(a, b), mul_mod_reference(a, b), "{:#x} * {:#x}", a, b);
+            }
+        }
+    }
+}
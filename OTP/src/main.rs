@@ -1,74 +1,550 @@
+mod ledger;
+mod mac;
+mod pad;
+mod shares;
+
 use std::env;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{self, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use common::STDIO;
+use common::inplace::same_file;
+use ledger::{Ledger, Use};
+use mac::Mac;
+use pad::{LAYOUT_LEN, Layout, PAD_ID_LEN, Pad, Role, Stream, decode_layout, encode_layout, format_id};
+
+/// The pad, looked up in the current directory.
+const PAD_FILE: &str = "key.key";
+
+/// Size of the input, pad and output buffers.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Default ledger of consumed pad ranges, in the current directory.
+const LEDGER_FILE: &str = "otp.ledger";
+
+/// Identifies files encrypted with a tracked pad offset.
+const MAGIC: &[u8; 6] = b"OTPPAD";
+/// Both versions hold the pad fingerprint, how the pad is split, and the
+/// message's offset and length. Version 2 marks a message followed by a MAC tag, keyed by the
+/// `mac::KEY_LEN` pad bytes after the message.
+const VERSION: u8 = 1;
+const VERSION_MAC: u8 = 2;
+/// Magic and version.
+const PREFIX_LEN: usize = MAGIC.len() + 1;
+/// Magic, version, pad fingerprint, layout (see `pad::encode_layout`), then
+/// big-endian offset and length.
+const HEADER_LEN: usize = PREFIX_LEN + PAD_ID_LEN + LAYOUT_LEN + 8 + 8;
+
+/// Where a message sits in the pad, as read from its header.
+struct Header {
+    pad_id: [u8; PAD_ID_LEN],
+    /// How the sender's ledger splits the pad.
+    layout: Option<Layout>,
+    offset: u64,
+    len: u64,
+    /// Whether a MAC tag follows the message.
+    authenticated: bool,
+}
+
+enum Mode {
+    /// No mode flag was given.
+    Unset,
+    /// XOR from offset 0 without a header or ledger entry, as older versions
+    /// did; only with --untracked.
+    Legacy,
+    Encrypt,
+    Decrypt,
+    /// Split a file into this many XOR shares.
+    Share(u8),
+    /// Rebuild a file from its shares.
+    Combine,
+}
 
-fn main() -> io::Result<()> {
+fn usage(program: &str) -> ! {
+    eprintln!("Usage:");
+    eprintln!("  {} -E [--ledger <file>] <input_file> <output_file>   encrypt from the next unused pad offset", program);
+    eprintln!("  {} -D [--ledger <file>] <input_file> <output_file>   decrypt a file made with -E", program);
+    eprintln!("  {} -E|-D [--ledger <file>] -over <input_file>", program);
+    eprintln!("  {} -E --mac ...              append a one-time MAC, checked by -D before any output", program);
+    eprintln!("  {} -D --mac ...              also refuse input without a MAC", program);
+    eprintln!("  {} -E|-D --burn ...          then overwrite the pad bytes used with random data", program);
+    eprintln!("  {} -E --raw ...              encrypt without a header; the offset is printed", program);
+    eprintln!("  {} -D --raw [--mac] [--offset N] ... decrypt headerless input from pad offset N (default 0)", program);
+    eprintln!("  {} --split A|B [--interleave <bytes>] [--ledger <file>]   share key.key with a peer", program);
+    eprintln!("  {} -S <N> <input_file> [<prefix>]   split into N shares, all needed to rebuild it", program);
+    eprintln!("  {} -C <output_file> <share>...       rebuild a file from all of its shares", program);
+    eprintln!("  {} --untracked [--raw] [--ledger <file>] <input_file> <output_file>   (legacy: XOR from pad offset 0, not recorded)", program);
+    eprintln!("  {} --untracked [--ledger <file>] -over <input_file>", program);
+    eprintln!("Files may be - for standard input or output. The ledger defaults to {}.", LEDGER_FILE);
+    std::process::exit(1);
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Determine mode and validate command line arguments.
-    // If the first argument is "-over", we'll overwrite the input file.
+    // With "-over", we'll overwrite the input file.
     let args: Vec<String> = env::args().collect();
-    let (input_path, output_path, atomic_overwrite) = match args.len() {
-        3 => {
-            if args[1] == "-over" {
-                (args[2].clone(), args[2].clone(), true)
-            } else {
-                (args[1].clone(), args[2].clone(), false)
-            }
-        },
-        _ => {
-            eprintln!("Usage:");
-            eprintln!("  {} <input_file> <output_file>", args[0]);
-            eprintln!("  {} -over <input_file>", args[0]);
-            std::process::exit(1);
+    let mut mode = Mode::Unset;
+    let mut ledger_path = None;
+    let mut raw = false;
+    let mut mac = false;
+    let mut burn = false;
+    let mut untracked = false;
+    let mut offset = None;
+    let mut split = None;
+    let mut interleave = None;
+    let mut atomic_overwrite = false;
+    let mut files = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-E" if files.is_empty() => mode = Mode::Encrypt,
+            "-D" if files.is_empty() => mode = Mode::Decrypt,
+            "-S" if files.is_empty() => match rest.next().and_then(|n| n.parse::<u8>().ok()).filter(|&n| n >= 2) {
+                Some(n) => mode = Mode::Share(n),
+                None => usage(&args[0]),
+            },
+            "-C" if files.is_empty() => mode = Mode::Combine,
+            "--untracked" if files.is_empty() => untracked = true,
+            "-over" if files.is_empty() => atomic_overwrite = true,
+            "--raw" if files.is_empty() => raw = true,
+            "--mac" if files.is_empty() => mac = true,
+            "--burn" if files.is_empty() => burn = true,
+            "--ledger" if files.is_empty() => match rest.next() {
+                Some(path) => ledger_path = Some(path.clone()),
+                None => usage(&args[0]),
+            },
+            "--offset" if files.is_empty() => match rest.next().and_then(|n| n.parse::<u64>().ok()) {
+                Some(n) => offset = Some(n),
+                None => usage(&args[0]),
+            },
+            "--split" if files.is_empty() => match rest.next().and_then(|role| Role::parse(role)) {
+                Some(role) => split = Some(role),
+                None => usage(&args[0]),
+            },
+            "--interleave" if files.is_empty() => match rest.next().and_then(|n| n.parse::<u64>().ok()).filter(|n| (1..=pad::MAX_BLOCK).contains(n)) {
+                Some(n) => interleave = Some(n),
+                None => usage(&args[0]),
+            },
+            _ => files.push(arg.clone()),
         }
+    }
+    let plain = !atomic_overwrite && !raw && !mac && !burn && !untracked && offset.is_none() && interleave.is_none();
+    match (&mode, &files[..]) {
+        (Mode::Share(_) | Mode::Combine, _) if !plain || ledger_path.is_some() || split.is_some() => usage(&args[0]),
+        (&Mode::Share(count), [input]) if input != STDIO => return shares::split(input, count, input),
+        (&Mode::Share(count), [input, prefix]) => return shares::split(input, count, prefix),
+        (Mode::Combine, [output, shares @ ..]) if shares.len() >= 2 => return shares::combine(output, shares),
+        (Mode::Share(_) | Mode::Combine, _) => usage(&args[0]),
+        _ => {}
+    }
+    if let Some(role) = split {
+        if !files.is_empty() || atomic_overwrite || raw || mac || burn || untracked || offset.is_some() || !matches!(mode, Mode::Unset) {
+            usage(&args[0]);
+        }
+        let layout = interleave.map_or(Layout::Halves, Layout::Interleave);
+        let ledger_path = ledger_path.unwrap_or_else(|| LEDGER_FILE.to_string());
+        return split_pad(Path::new(&ledger_path), layout, role);
+    }
+    if interleave.is_some() {
+        usage(&args[0]);
+    }
+    let (input_path, output_path) = match (atomic_overwrite, &files[..]) {
+        (true, [input]) => (input.clone(), input.clone()),
+        (false, [input, output]) => (input.clone(), output.clone()),
+        _ => usage(&args[0]),
     };
+    match (&mode, untracked) {
+        (Mode::Unset, true) => mode = Mode::Legacy,
+        (_, true) => usage(&args[0]),
+        _ => {}
+    }
+    if matches!(mode, Mode::Unset) {
+        eprintln!("Error: give -E to encrypt or -D to decrypt. XORing from pad offset 0, as older versions did, \
+                   reuses pad bytes and needs --untracked.");
+        std::process::exit(1);
+    }
+    let tracked = !matches!(mode, Mode::Legacy);
+    if (!tracked && (mac || burn)) || (offset.is_some() && !(raw && matches!(mode, Mode::Decrypt))) {
+        usage(&args[0]);
+    }
+    let ledger_path = ledger_path.unwrap_or_else(|| LEDGER_FILE.to_string());
 
-    // Read the entire input file into memory.
-    let input_data = fs::read(&input_path)?;
-    
-    // Read the key from the key file "key.key" in the current directory.
-    let key_data = fs::read("key.key")?;
-    
-    // Ensure the key is at least as large as the input data.
-    if key_data.len() < input_data.len() {
-        eprintln!("Error: key is smaller than the input file.");
+    if atomic_overwrite && input_path == STDIO {
+        eprintln!("Error: -over needs a file, not standard input.");
+        std::process::exit(1);
+    }
+
+    if !atomic_overwrite && input_path != STDIO && output_path != STDIO
+        && same_file(Path::new(&input_path), Path::new(&output_path))? {
+        eprintln!("Error: {} is both the input and the output; use -over to replace it.", input_path);
         std::process::exit(1);
     }
-    
-    // XOR each byte of the input with the corresponding byte from the key.
-    let result: Vec<u8> = input_data.iter()
-        .enumerate()
-        .map(|(i, &byte)| byte ^ key_data[i])
-        .collect();
+
+    let (mut input, input_len) = open_input(&input_path)?;
+
+    // Check everything and claim the pad range before any output exists, so
+    // a refused run leaves nothing behind.
+    let job = match mode {
+        Mode::Legacy => prepare_legacy(input_len, Path::new(&ledger_path))?,
+        Mode::Encrypt => prepare_encrypt(input_len, Path::new(&ledger_path), raw, mac)?,
+        Mode::Decrypt if raw => prepare_decrypt_raw(&mut input, input_len, Path::new(&ledger_path), offset.unwrap_or(0), mac)?,
+        Mode::Decrypt => prepare_decrypt(&mut input, input_len, Path::new(&ledger_path), mac)?,
+        Mode::Unset | Mode::Share(_) | Mode::Combine => unreachable!(),
+    };
+
+    let used = job.used;
 
     if atomic_overwrite {
         // For atomic overwrite, write the processed data to a temporary file
         // in the same directory as the input file.
         let tmp_path = format!("{}.tmp", input_path);
 
-        {
+        let written = (|| -> Result<(), Box<dyn std::error::Error>> {
             // Open (or create) the temporary file, truncating it if it exists.
-            let mut tmp_file = OpenOptions::new()
+            let tmp_file = OpenOptions::new()
                 .write(true)
                 .create(true)
                 .truncate(true)
                 .open(&tmp_path)?;
 
             // Write the XOR result to the temporary file.
-            tmp_file.write_all(&result)?;
+            let mut writer = BufWriter::with_capacity(BUF_SIZE, &tmp_file);
+            job.run(&mut input, &mut writer)?;
             // Flush and sync the file to ensure all data is committed to disk.
-            tmp_file.flush()?;
+            writer.flush()?;
             tmp_file.sync_all()?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
         }
 
         // Atomically replace the input file with the temporary file.
         // On most platforms, fs::rename is atomic if both files reside in the same directory.
         fs::rename(&tmp_path, &input_path)?;
+        if burn {
+            sync_parent(Path::new(&input_path))?;
+        }
+    } else if output_path == STDIO {
+        let mut stdout = BufWriter::with_capacity(BUF_SIZE, io::stdout().lock());
+        job.run(&mut input, &mut stdout)?;
+        stdout.flush()?;
     } else {
         // Normal mode: write the processed data to the specified output file.
-        fs::write(&output_path, result)?;
+        let mut writer = BufWriter::with_capacity(BUF_SIZE, File::create(&output_path)?);
+        job.run(&mut input, &mut writer)?;
+        writer.flush()?;
+        if burn {
+            // The output must be safely on disk before the pad bytes that
+            // could recreate it are gone.
+            writer.get_ref().sync_all()?;
+            sync_parent(Path::new(&output_path))?;
+        }
+    }
+
+    if burn && let Some((stream, start, end)) = used {
+        pad::destroy(Path::new(PAD_FILE), &stream, start, end)?;
+        eprintln!("Destroyed {} ({}).", stream.describe(start, end), PAD_FILE);
+    }
+
+    Ok(())
+}
+
+/// Records in the ledger that `key.key` is shared with a peer, laid out with
+/// `layout`, and that this side is `role`. Each side then encrypts only with
+/// its own share, so both can send without coordinating. The pad must not
+/// have been used yet, as earlier messages may lie in either share.
+fn split_pad(ledger_path: &Path, layout: Layout, role: Role) -> Result<(), Box<dyn std::error::Error>> {
+    let pad = Pad::open(Path::new(PAD_FILE))?;
+    let mut ledger = Ledger::open(ledger_path)?;
+    if let Some((layout, role)) = ledger.split(&pad)? {
+        return Err(format!("Pad {} is already split ({}), and this side is {}.", format_id(pad.id()), layout, role).into());
+    }
+    if ledger.has_ranges(pad.id()) {
+        return Err(format!("Pad {} has already been used; a pad can only be split before its first message.",
+                           format_id(pad.id())).into());
     }
-    
+    pad.check_layout(layout)?;
+    ledger.set_split(pad.id(), layout, role)?;
+
+    let own = pad.stream(Some((layout, role)));
+    let peer = pad.stream(Some((layout, role.peer())));
+    println!("Split pad {} ({}) as {}. This side is {}.", format_id(pad.id()), PAD_FILE, layout, role);
+    println!("Sending uses {}: {} bytes.", own, own.len());
+    println!("Receiving uses {}: {} bytes.", peer, peer.len());
+    println!("The peer must run --split {} with the same layout.", role.peer());
     Ok(())
 }
 
+/// Syncs the directory holding `path`, so that a new directory entry for it
+/// survives a crash.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()
+}
+
+/// Opens the input and returns it with its length. Standard input, and any
+/// other input that is not a regular file, is first copied to an unnamed
+/// temporary file, as its length has to be known before any pad bytes are
+/// claimed.
+fn open_input(path: &str) -> Result<(File, u64), Box<dyn std::error::Error>> {
+    let mut file = if path == STDIO {
+        spool(&mut io::stdin().lock())?
+    } else {
+        let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        if file.metadata()?.is_file() { file } else { spool(&mut &file)? }
+    };
+    let len = file.metadata()?.len();
+    file.rewind()?;
+    Ok((file, len))
+}
+
+fn spool(input: &mut impl Read) -> io::Result<File> {
+    let mut spool = tempfile::tempfile()?;
+    io::copy(input, &mut spool)?;
+    Ok(spool)
+}
+
+/// What to write: a header, then `len` input bytes XORed with `key`, then
+/// the tag of `mac` over both, if set.
+struct Job {
+    header: Vec<u8>,
+    key: Box<dyn Read>,
+    len: u64,
+    mac: Option<Mac>,
+    /// The stream and byte range consumed, unless the run is untracked.
+    used: Option<(Stream, u64, u64)>,
+}
+
+impl Job {
+    /// Streams the rest of `input` through fixed-size buffers, so memory use
+    /// does not depend on the size of the input or the pad.
+    fn run(mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), Box<dyn std::error::Error>> {
+        output.write_all(&self.header)?;
+        if let Some(mac) = &mut self.mac {
+            mac.update(&self.header);
+        }
+        let mut data = vec![0u8; BUF_SIZE];
+        let mut key = vec![0u8; BUF_SIZE];
+        let mut remaining = self.len;
+        while remaining > 0 {
+            let n = remaining.min(BUF_SIZE as u64) as usize;
+            input.read_exact(&mut data[..n]).map_err(|e| format!("Cannot read input: {}", e))?;
+            self.key.read_exact(&mut key[..n]).map_err(|e| format!("Cannot read pad: {}", e))?;
+            data[..n].iter_mut().zip(&key[..n]).for_each(|(byte, &k)| *byte ^= k);
+            if let Some(mac) = &mut self.mac {
+                mac.update(&data[..n]);
+            }
+            output.write_all(&data[..n])?;
+            remaining -= n as u64;
+        }
+        if let Some(mac) = self.mac {
+            output.write_all(&mac.finalize())?;
+        }
+        Ok(())
+    }
+}
+
+/// The original behaviour: XOR with the key file from offset 0. Every use
+/// consumes the same pad bytes, so this only suits files made that way. It is
+/// refused when the ledger shows any of those bytes in use, and it records
+/// nothing itself.
+fn prepare_legacy(len: u64, ledger_path: &Path) -> Result<Job, Box<dyn std::error::Error>> {
+    let key = File::open(PAD_FILE)?;
+
+    // Ensure the key is at least as large as the input data.
+    let key_len = key.metadata()?.len();
+    if key_len < len {
+        return Err("key is smaller than the input file.".into());
+    }
+    // A key too small to hold a fingerprint has never been used with -E.
+    if len > 0 && key_len > pad::RESERVED_LEN {
+        let pad = Pad::open(Path::new(PAD_FILE))?;
+        let ledger = Ledger::open(ledger_path)?;
+        if let Some((layout, _)) = ledger.split(&pad)? {
+            return Err(format!("Pad {} is split ({}) with a peer, so offset 0 belongs to a share; use -E or -D.",
+                               format_id(pad.id()), layout).into());
+        }
+        let stream = pad.stream(None);
+        if ledger.in_use(&stream, 0, len) {
+            return Err(format!("XORing from offset 0 would reuse {}, which {} records as used. Use -E or -D.",
+                               stream.describe(0, len), ledger_path.display()).into());
+        }
+        eprintln!("Warning: {} were used without a ledger entry; -E may hand them out again.", stream.describe(0, len));
+    }
+    Ok(Job { header: Vec::new(), key: Box::new(key.take(len)), len, mac: None, used: None })
+}
+
+/// Pad bytes a message of `len` bytes consumes, including its MAC key.
+fn pad_span(len: u64, authenticated: bool) -> u64 {
+    if authenticated { len + mac::KEY_LEN as u64 } else { len }
+}
+
+/// Reads the MAC key that follows the `len` message bytes at `offset`.
+fn mac_key(pad: &mut Pad, stream: &Stream, offset: u64, len: u64) -> Result<Mac, Box<dyn std::error::Error>> {
+    let mut key = [0u8; mac::KEY_LEN];
+    pad.read_at(stream, offset + len, &mut key)?;
+    Ok(Mac::new(&key))
+}
+
+/// Encrypts `len` bytes with pad bytes no earlier run has consumed. The range
+/// is recorded in the ledger before any output is written. The header names
+/// the pad and the range so the receiver needs nothing else; with `raw` there
+/// is no header, and the offset has to be passed on out of band. With
+/// `authenticated`, the pad bytes after the message key a MAC over the header
+/// and ciphertext. On a split pad, only this side's share is used, and the
+/// header holds the pad offset the message starts at.
+fn prepare_encrypt(len: u64, ledger_path: &Path, raw: bool, authenticated: bool) -> Result<Job, Box<dyn std::error::Error>> {
+    let mut pad = Pad::open(Path::new(PAD_FILE))?;
+    let mut ledger = Ledger::open(ledger_path)?;
+    let stream = pad.stream(ledger.split(&pad)?);
+    let span = pad_span(len, authenticated);
+    let offset = ledger.allocate(&stream, span)?;
+    let mac = if authenticated { Some(mac_key(&mut pad, &stream, offset, len)?) } else { None };
+    let physical = stream.physical(offset);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    if !raw {
+        header.extend_from_slice(MAGIC);
+        header.push(if authenticated { VERSION_MAC } else { VERSION });
+        header.extend_from_slice(pad.id());
+        header.extend_from_slice(&encode_layout(stream.layout()));
+        header.extend_from_slice(&physical.to_be_bytes());
+        header.extend_from_slice(&len.to_be_bytes());
+    }
+    eprintln!("Used {} ({}); {} bytes remain.",
+              stream.describe(offset, offset + span), pad.path().display(), stream.len() - offset - span);
+    if raw {
+        eprintln!("Decrypt with: -D --raw{} --offset {}", if authenticated { " --mac" } else { "" }, physical);
+    }
+    let used = Some((stream, offset, offset + span));
+    Ok(Job { header, key: Box::new(pad.into_reader(&stream, offset, len)?), len, mac, used })
+}
+
+/// Reads the header at the start of `input`, which is `input_len` bytes long,
+/// and returns it with its raw bytes and the length of the message that
+/// follows.
+fn read_header(input: &mut impl Read, input_len: u64) -> Result<(Header, Vec<u8>, u64), Box<dyn std::error::Error>> {
+    let not_otp = "Input is not an encrypted OTP file. Use --raw for files without a header.";
+    let mut raw = vec![0u8; PREFIX_LEN];
+    if input_len < PREFIX_LEN as u64 {
+        return Err(not_otp.into());
+    }
+    input.read_exact(&mut raw)?;
+    if &raw[..MAGIC.len()] != MAGIC {
+        return Err(not_otp.into());
+    }
+    let version = raw[MAGIC.len()];
+    let authenticated = match version {
+        VERSION => false,
+        VERSION_MAC => true,
+        version => return Err(format!("Unsupported OTP file version {}.", version).into()),
+    };
+    let trailer = if authenticated { mac::TAG_LEN } else { 0 };
+    if input_len < (HEADER_LEN + trailer) as u64 {
+        return Err("Input is truncated: the OTP header is incomplete.".into());
+    }
+    raw.resize(HEADER_LEN, 0);
+    input.read_exact(&mut raw[PREFIX_LEN..])?;
+    let fields = &raw[PREFIX_LEN..];
+    let u64_at = |at: usize| u64::from_be_bytes(fields[at..at + 8].try_into().unwrap());
+    let body_len = input_len - (HEADER_LEN + trailer) as u64;
+
+    let mut pad_id = [0u8; PAD_ID_LEN];
+    pad_id.copy_from_slice(&fields[..PAD_ID_LEN]);
+    let layout = decode_layout(fields[PAD_ID_LEN..PAD_ID_LEN + LAYOUT_LEN].try_into().unwrap())
+        .ok_or("Input has an invalid pad layout in its OTP header.")?;
+    let at = PAD_ID_LEN + LAYOUT_LEN;
+    let header = Header { pad_id, layout, offset: u64_at(at), len: u64_at(at + 8), authenticated };
+    Ok((header, raw, body_len))
+}
+
+/// Decrypts a file made by `prepare_encrypt`, using the pad slice its header
+/// names. The header must record the pad split the same way as the ledger
+/// does. With `require_mac`, input without a MAC is refused, so that one
+/// cannot be stripped off unnoticed.
+fn prepare_decrypt(input: &mut File, input_len: u64, ledger_path: &Path, require_mac: bool) -> Result<Job, Box<dyn std::error::Error>> {
+    let (header, raw_header, body_len) = read_header(input, input_len)?;
+    if require_mac && !header.authenticated {
+        return Err("Input has no MAC, so it cannot be authenticated.".into());
+    }
+    let pad = Pad::open(Path::new(PAD_FILE))?;
+    if &header.pad_id != pad.id() {
+        return Err(format!("Input was encrypted with pad {}, but {} is pad {}.",
+                           format_id(&header.pad_id), PAD_FILE, format_id(pad.id())).into());
+    }
+    if header.len != body_len {
+        return Err(format!("Input should hold {} bytes after the header but holds {}; it is truncated or damaged.",
+                           header.len, body_len).into());
+    }
+    // Peers that split the pad differently would read each other's messages
+    // from the wrong pad bytes, and encrypt over each other's shares.
+    let ledger = Ledger::open(ledger_path)?;
+    let layout = ledger.split(&pad)?.map(|(layout, _)| layout);
+    if header.layout != layout {
+        let describe = |layout: Option<Layout>| layout.map_or("not split".to_string(), |layout| format!("split as {}", layout));
+        return Err(format!("Input was encrypted with pad {} {}, but in {} it is {}. \
+                            Both sides must run --split with the same layout.",
+                           format_id(pad.id()), describe(header.layout), ledger_path.display(), describe(layout)).into());
+    }
+    prepare_range(pad, ledger, header.offset, body_len, header.authenticated.then_some((input, &raw_header[..])))
+}
+
+/// Decrypts headerless input that was encrypted from pad offset `offset`,
+/// followed by a MAC tag if `authenticated`.
+fn prepare_decrypt_raw(input: &mut File, input_len: u64, ledger_path: &Path, offset: u64, authenticated: bool) -> Result<Job, Box<dyn std::error::Error>> {
+    let trailer = if authenticated { mac::TAG_LEN as u64 } else { 0 };
+    let len = input_len.checked_sub(trailer).ok_or("Input is too short to hold a MAC.")?;
+    let pad = Pad::open(Path::new(PAD_FILE))?;
+    prepare_range(pad, Ledger::open(ledger_path)?, offset, len, authenticated.then_some((input, &[][..])))
+}
+
+/// Sets up decryption with `len` pad bytes from pad offset `physical` and
+/// records the range in the ledger, so that later encryptions never reuse it.
+/// On a split pad, the offset tells which side's share the message was
+/// encrypted with. When `auth` holds the input, positioned after `header`,
+/// the MAC over the header and ciphertext is checked before anything is
+/// recorded, and the input is left where it was.
+fn prepare_range(mut pad: Pad, mut ledger: Ledger, physical: u64, len: u64, auth: Option<(&mut File, &[u8])>)
+                 -> Result<Job, Box<dyn std::error::Error>> {
+    let (stream, offset) = pad.locate(ledger.split(&pad)?.map(|(layout, _)| layout), physical);
+    let span = pad_span(len, auth.is_some());
+    stream.check_range(offset, span)?;
+    let end = offset + span;
+    if ledger.collides(&stream, offset, end) {
+        eprintln!("Warning: {} overlap a range this side used to encrypt. \
+                   The pad has been reused and both messages may be exposed.", stream.describe(offset, end));
+    }
+    if let Some((input, header)) = auth {
+        let mut mac = mac_key(&mut pad, &stream, offset, len)?;
+        mac.update(header);
+        let start = input.stream_position()?;
+        let mut buf = vec![0u8; BUF_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(BUF_SIZE as u64) as usize;
+            input.read_exact(&mut buf[..n]).map_err(|e| format!("Cannot read input: {}", e))?;
+            mac.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+        let mut tag = [0u8; mac::TAG_LEN];
+        input.read_exact(&mut tag)?;
+        if !mac.verify(&tag) {
+            return Err("Authentication failed: the input was modified, or was not encrypted with this part of the pad. \
+                        Nothing was written.".into());
+        }
+        input.seek(SeekFrom::Start(start))?;
+    }
+
+    // Every decrypted range is recorded, with or without a MAC: pad bytes the
+    // sender consumed must never look free to -E. A forged header can at
+    // worst waste unused pad, which --mac prevents.
+    let own = ledger.ranges(&stream).any(|range| range.kind == Use::Encrypt && (range.start, range.end) == (offset, end));
+    if span > 0 && !own && !ledger.has_decrypted(&stream, offset, end) {
+        ledger.record(&stream, offset, end, Use::Decrypt)?;
+    }
+    let used = Some((stream, offset, end));
+    Ok(Job { header: Vec::new(), key: Box::new(pad.into_reader(&stream, offset, len)?), len, mac: None, used })
+}
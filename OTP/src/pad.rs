@@ -0,0 +1,498 @@
+use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Length of the pad fingerprint.
+pub const PAD_ID_LEN: usize = 8;
+
+/// Bytes at the end of every pad that identify it. They are never used to
+/// encrypt, so the fingerprint reveals nothing about pad material in use.
+pub const RESERVED_LEN: u64 = 32;
+
+/// A one-time pad file.
+pub struct Pad {
+    path: PathBuf,
+    file: File,
+    id: [u8; PAD_ID_LEN],
+    usable: u64,
+}
+
+/// One of the two parties sharing a split pad.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    A,
+    B,
+}
+
+/// How a split pad is divided between the two directions of traffic.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// A encrypts with the first half of the pad, B with the second.
+    Halves,
+    /// The pad is cut into blocks of this many bytes, which A and B take in
+    /// turn, starting with A.
+    Interleave(u64),
+}
+
+/// Largest interleave block of any pad: each side needs at least one block,
+/// so a pad's blocks can be at most half its usable bytes long.
+pub const MAX_BLOCK: u64 = (u64::MAX - RESERVED_LEN) / 2;
+
+/// Length of a layout in a message header: a kind byte, then the block size
+/// as a big-endian integer, zero unless the pad is interleaved.
+pub const LAYOUT_LEN: usize = 1 + 8;
+
+/// Header form of `layout`, where `None` stands for a pad that is not split.
+pub fn encode_layout(layout: Option<Layout>) -> [u8; LAYOUT_LEN] {
+    let (kind, block) = match layout {
+        None => (0, 0),
+        Some(Layout::Halves) => (1, 0),
+        Some(Layout::Interleave(block)) => (2, block),
+    };
+    let mut bytes = [0u8; LAYOUT_LEN];
+    bytes[0] = kind;
+    bytes[1..].copy_from_slice(&block.to_be_bytes());
+    bytes
+}
+
+/// Parses the header form written by `encode_layout`, or returns `None` if it
+/// is not valid.
+pub fn decode_layout(bytes: &[u8; LAYOUT_LEN]) -> Option<Option<Layout>> {
+    let block = u64::from_be_bytes(bytes[1..].try_into().unwrap());
+    match (bytes[0], block) {
+        (0, 0) => Some(None),
+        (1, 0) => Some(Some(Layout::Halves)),
+        (2, block) if (1..=MAX_BLOCK).contains(&block) => Some(Some(Layout::Interleave(block))),
+        _ => None,
+    }
+}
+
+/// The pad bytes one sender encrypts with: the whole pad, or one party's
+/// share of a split pad. Offsets into a stream are what the ledger records;
+/// `physical` maps them to offsets in the pad file.
+#[derive(Clone, Copy)]
+pub struct Stream {
+    id: [u8; PAD_ID_LEN],
+    usable: u64,
+    share: Option<(Layout, Role)>,
+}
+
+/// Hex form of a fingerprint, for messages and the ledger.
+pub fn format_id(id: &[u8; PAD_ID_LEN]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Pad {
+    /// Opens the pad at `path` and computes its fingerprint: SHA-256 over a
+    /// fixed label and the reserved bytes at its end, truncated to `PAD_ID_LEN`.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file = File::open(path).map_err(|e| format!("Cannot open pad {}: {}", path.display(), e))?;
+        let len = file.metadata()?.len();
+        if len <= RESERVED_LEN {
+            return Err(format!("Pad {} is too small; it needs more than {} bytes.", path.display(), RESERVED_LEN).into());
+        }
+        let usable = len - RESERVED_LEN;
+        let mut reserved = [0u8; RESERVED_LEN as usize];
+        file.seek(SeekFrom::Start(usable))?;
+        file.read_exact(&mut reserved)?;
+
+        let digest = Sha256::new().chain_update(b"otp pad id v1").chain_update(reserved).finalize();
+        let mut id = [0u8; PAD_ID_LEN];
+        id.copy_from_slice(&digest[..PAD_ID_LEN]);
+        Ok(Pad { path: path.to_path_buf(), file, id, usable })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn id(&self) -> &[u8; PAD_ID_LEN] {
+        &self.id
+    }
+
+    /// Fails unless the pad can be split with `layout`: interleave blocks may
+    /// be at most half the pad long, so that each side gets a block.
+    pub fn check_layout(&self, layout: Layout) -> Result<(), Box<dyn std::error::Error>> {
+        match layout {
+            Layout::Interleave(block) if block > self.usable / 2 => {
+                Err(format!("Pad {} holds {} usable bytes, so it cannot be interleaved in blocks of {} bytes; the most is {}.",
+                            format_id(&self.id), self.usable, block, self.usable / 2).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The stream of the whole pad, or of `share` when the pad is split.
+    pub fn stream(&self, share: Option<(Layout, Role)>) -> Stream {
+        Stream { id: self.id, usable: self.usable, share }
+    }
+
+    /// Finds the stream and stream offset of pad offset `physical`, for a pad
+    /// split with `layout`, if any.
+    pub fn locate(&self, layout: Option<Layout>, physical: u64) -> (Stream, u64) {
+        match layout {
+            None => (self.stream(None), physical),
+            Some(Layout::Halves) => {
+                let half = self.usable / 2;
+                if physical < half {
+                    (self.stream(Some((Layout::Halves, Role::A))), physical)
+                } else {
+                    (self.stream(Some((Layout::Halves, Role::B))), physical - half)
+                }
+            }
+            Some(Layout::Interleave(block)) => {
+                let index = physical / block;
+                let role = if index.is_multiple_of(2) { Role::A } else { Role::B };
+                (self.stream(Some((Layout::Interleave(block), role))), index / 2 * block + physical % block)
+            }
+        }
+    }
+
+    /// Fills `buf` with bytes of `stream` starting at `offset`.
+    pub fn read_at(&mut self, stream: &Stream, offset: u64, buf: &mut [u8]) -> Result<(), Box<dyn std::error::Error>> {
+        stream.check_range(offset, buf.len() as u64)?;
+        let mut filled = 0;
+        for (physical, len) in stream.runs(offset, offset + buf.len() as u64) {
+            self.file.seek(SeekFrom::Start(physical))?;
+            self.file.read_exact(&mut buf[filled..filled + len as usize])?;
+            filled += len as usize;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader over `len` bytes of `stream` starting at `offset`.
+    pub fn into_reader(self, stream: &Stream, offset: u64, len: u64) -> Result<PadReader, Box<dyn std::error::Error>> {
+        stream.check_range(offset, len)?;
+        Ok(PadReader { file: self.file, stream: *stream, pos: offset, end: offset + len })
+    }
+}
+
+impl Stream {
+    /// Name of the stream in the ledger: the pad fingerprint, followed by the
+    /// sender for a share of a split pad.
+    pub fn key(&self) -> String {
+        match self.share {
+            None => format_id(&self.id),
+            Some((_, role)) => format!("{}:{}", format_id(&self.id), role),
+        }
+    }
+
+    /// How the pad is split, if it is.
+    pub fn layout(&self) -> Option<Layout> {
+        self.share.map(|(layout, _)| layout)
+    }
+
+    /// Number of bytes in the stream.
+    pub fn len(&self) -> u64 {
+        match self.share {
+            None => self.usable,
+            Some((Layout::Halves, Role::A)) => self.usable / 2,
+            Some((Layout::Halves, Role::B)) => self.usable - self.usable / 2,
+            Some((Layout::Interleave(block), role)) => {
+                // A pair of blocks longer than the pad leaves it all in the first block.
+                let (pairs, rest) = match block.checked_mul(2) {
+                    Some(pair) => (self.usable / pair, self.usable % pair),
+                    None => (0, self.usable),
+                };
+                pairs * block + match role {
+                    Role::A => rest.min(block),
+                    Role::B => rest.saturating_sub(block),
+                }
+            }
+        }
+    }
+
+    /// Pad offset of stream offset `offset`. Offsets past the end of the
+    /// stream map past the end of the pad, never back into it.
+    pub fn physical(&self, offset: u64) -> u64 {
+        match self.share {
+            None | Some((Layout::Halves, Role::A)) => offset,
+            Some((Layout::Halves, Role::B)) => (self.usable / 2).saturating_add(offset),
+            Some((Layout::Interleave(block), role)) => {
+                let index = (offset / block).checked_mul(2).and_then(|index| index.checked_add(if role == Role::B { 1 } else { 0 }));
+                index.and_then(|index| index.checked_mul(block))
+                    .and_then(|start| start.checked_add(offset % block))
+                    .unwrap_or(u64::MAX)
+            }
+        }
+    }
+
+    /// The pieces of pad, as offset and length, that hold stream bytes
+    /// `start..end`, in order.
+    fn runs(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let mut pos = start;
+        std::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let len = match self.share {
+                Some((Layout::Interleave(block), _)) => (block - pos % block).min(end - pos),
+                _ => end - pos,
+            };
+            let run = (self.physical(pos), len);
+            pos += len;
+            Some(run)
+        })
+    }
+
+    /// Fails unless bytes `offset..offset + len` lie in the stream.
+    pub fn check_range(&self, offset: u64, len: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if offset.checked_add(len).is_none_or(|end| end > self.len()) {
+            return Err(format!("{} has no bytes {}..{}; it holds {} bytes.",
+                               self, offset, offset.saturating_add(len), self.len()).into());
+        }
+        Ok(())
+    }
+
+    /// Describes bytes `start..end` of the stream, with where they lie in
+    /// the pad when it is split.
+    pub fn describe(&self, start: u64, end: u64) -> String {
+        let range = format!("bytes {}..{} of {}", start, end, self);
+        match self.share {
+            Some((layout, _)) if start < end => {
+                let (first, last) = (self.physical(start), self.physical(end - 1) + 1);
+                match layout {
+                    Layout::Halves => format!("{} (pad bytes {}..{})", range, first, last),
+                    Layout::Interleave(_) => format!("{} (in alternate blocks within pad bytes {}..{})", range, first, last),
+                }
+            }
+            _ => range,
+        }
+    }
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.share {
+            None => write!(f, "pad {}", format_id(&self.id)),
+            Some((_, role)) => write!(f, "the {}->{} share of pad {}", role, role.peer(), format_id(&self.id)),
+        }
+    }
+}
+
+impl Role {
+    pub fn peer(self) -> Role {
+        match self {
+            Role::A => Role::B,
+            Role::B => Role::A,
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Role> {
+        match text {
+            "A" | "a" => Some(Role::A),
+            "B" | "b" => Some(Role::B),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Role::A => "A",
+            Role::B => "B",
+        })
+    }
+}
+
+impl Layout {
+    /// Parses the ledger form written by `Display`.
+    pub fn parse(text: &str) -> Option<Layout> {
+        match text.strip_prefix("interleave:") {
+            Some(block) => block.parse().ok().filter(|block| (1..=MAX_BLOCK).contains(block)).map(Layout::Interleave),
+            None => (text == "halves").then_some(Layout::Halves),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::Halves => f.write_str("halves"),
+            Layout::Interleave(block) => write!(f, "interleave:{}", block),
+        }
+    }
+}
+
+/// Reads a range of a stream, following it across the pad.
+pub struct PadReader {
+    file: File,
+    stream: Stream,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for PadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((physical, len)) = self.stream.runs(self.pos, self.end).next() else {
+            return Ok(0);
+        };
+        let want = len.min(buf.len() as u64) as usize;
+        self.file.seek(SeekFrom::Start(physical))?;
+        let n = self.file.read(&mut buf[..want])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Overwrites bytes `start..end` of `stream` in the pad at `path` with random
+/// data and syncs them to disk, so that the messages they encrypted can no
+/// longer be recovered from the pad. Random data rather than zeros, so that
+/// the range cannot turn into a key that leaves plaintext unchanged.
+pub fn destroy(path: &Path, stream: &Stream, start: u64, end: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = OpenOptions::new().write(true).open(path)
+        .map_err(|e| format!("Cannot open pad {} for writing: {}", path.display(), e))?;
+    let mut buf = vec![0u8; 64 * 1024];
+    for (physical, len) in stream.runs(start, end) {
+        file.seek(SeekFrom::Start(physical))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            OsRng.fill_bytes(&mut buf[..n]);
+            file.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+    }
+    file.sync_data()
+        .map_err(|e| format!("Cannot sync pad {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// A pad of `usable` bytes numbered 0, 1, 2, ... (mod 251), followed by
+    /// its reserved bytes.
+    pub fn test_pad(usable: u64) -> (NamedTempFile, Pad) {
+        let mut file = NamedTempFile::new().unwrap();
+        let bytes: Vec<u8> = (0..usable + RESERVED_LEN).map(|i| (i % 251) as u8).collect();
+        file.write_all(&bytes).unwrap();
+        let pad = Pad::open(file.path()).unwrap();
+        (file, pad)
+    }
+
+    const LAYOUTS: [Layout; 5] = [Layout::Halves, Layout::Interleave(1), Layout::Interleave(3),
+                                  Layout::Interleave(64), Layout::Interleave(1000)];
+
+    #[test]
+    fn shares_divide_the_pad() {
+        for usable in [1, 2, 7, 64, 129, 1001] {
+            let (_file, pad) = test_pad(usable);
+            for layout in LAYOUTS {
+                let (a, b) = (pad.stream(Some((layout, Role::A))), pad.stream(Some((layout, Role::B))));
+                assert_eq!(a.len() + b.len(), usable, "{} bytes, {}", usable, layout);
+                let mut seen = vec![false; usable as usize];
+                for stream in [a, b] {
+                    for x in 0..stream.len() {
+                        let physical = stream.physical(x);
+                        assert!(physical < usable, "{} bytes, {}: {} -> {}", usable, layout, x, physical);
+                        assert!(!seen[physical as usize], "{} bytes, {}: pad byte {} used twice", usable, layout, physical);
+                        seen[physical as usize] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn locate_inverts_physical() {
+        for usable in [1, 2, 7, 64, 129, 1001] {
+            let (_file, pad) = test_pad(usable);
+            let whole = pad.stream(None);
+            for x in 0..whole.len() {
+                let (stream, offset) = pad.locate(None, whole.physical(x));
+                assert_eq!((stream.key(), offset), (whole.key(), x));
+            }
+            for layout in LAYOUTS {
+                for role in [Role::A, Role::B] {
+                    let share = pad.stream(Some((layout, role)));
+                    for x in 0..share.len() {
+                        let (stream, offset) = pad.locate(Some(layout), share.physical(x));
+                        assert_eq!((stream.key(), offset), (share.key(), x), "{} bytes, {}, {}", usable, layout, role);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runs_split_at_block_boundaries() {
+        let (_file, pad) = test_pad(101);
+        for layout in LAYOUTS {
+            for role in [Role::A, Role::B] {
+                let share = pad.stream(Some((layout, role)));
+                let len = share.len();
+                let ranges = [(0, len), (1, len.saturating_sub(1)), (2, 5), (3, 4), (0, 0)];
+                for (start, end) in ranges.into_iter().filter(|&(start, end)| start <= end && end <= len) {
+                    let mut physical = Vec::new();
+                    for (run_start, len) in share.runs(start, end) {
+                        if let Layout::Interleave(block) = layout {
+                            assert!(run_start % block + len <= block, "{}: run crosses a block boundary", layout);
+                        }
+                        physical.extend(run_start..run_start + len);
+                    }
+                    let expected: Vec<u64> = (start..end).map(|x| share.physical(x)).collect();
+                    assert_eq!(physical, expected, "{}, {}, {}..{}", layout, role, start, end);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_follow_the_share_across_blocks() {
+        let (_file, mut pad) = test_pad(100);
+        let share = pad.stream(Some((Layout::Interleave(3), Role::B)));
+        let mut buf = [0u8; 10];
+        pad.read_at(&share, 2, &mut buf).unwrap();
+        let expected: Vec<u8> = (2..12).map(|x| (share.physical(x) % 251) as u8).collect();
+        assert_eq!(buf.to_vec(), expected);
+
+        let mut read = Vec::new();
+        pad.into_reader(&share, 2, 10).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn layout_header_round_trip() {
+        for layout in [None, Some(Layout::Halves), Some(Layout::Interleave(1)), Some(Layout::Interleave(MAX_BLOCK))] {
+            assert!(decode_layout(&encode_layout(layout)) == Some(layout));
+        }
+        assert!(decode_layout(&encode_layout(Some(Layout::Interleave(MAX_BLOCK + 1)))).is_none());
+        assert!(decode_layout(&encode_layout(Some(Layout::Interleave(u64::MAX)))).is_none());
+        let mut bytes = encode_layout(Some(Layout::Interleave(5)));
+        bytes[0] = 3;
+        assert!(decode_layout(&bytes).is_none());
+        bytes[0] = 1;
+        assert!(decode_layout(&bytes).is_none());
+        assert!(decode_layout(&[2, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn blocks_are_at_most_half_the_pad() {
+        let (_file, pad) = test_pad(101);
+        assert!(pad.check_layout(Layout::Halves).is_ok());
+        assert!(pad.check_layout(Layout::Interleave(50)).is_ok());
+        assert!(pad.check_layout(Layout::Interleave(51)).is_err());
+        assert!(pad.check_layout(Layout::Interleave(u64::MAX)).is_err());
+
+        assert!(Layout::parse(&format!("interleave:{}", MAX_BLOCK)) == Some(Layout::Interleave(MAX_BLOCK)));
+        assert!(Layout::parse(&format!("interleave:{}", MAX_BLOCK + 1)).is_none());
+        assert!(Layout::parse("interleave:0").is_none());
+
+        // Blocks too large for the pad must not overflow, whatever the caller.
+        for block in [MAX_BLOCK, MAX_BLOCK + 1, u64::MAX] {
+            let (a, b) = (pad.stream(Some((Layout::Interleave(block), Role::A))), pad.stream(Some((Layout::Interleave(block), Role::B))));
+            assert_eq!((a.len(), b.len()), (101, 0));
+            assert_eq!(a.physical(100), 100);
+            assert!(b.physical(0) >= 101);
+            assert!(b.check_range(0, 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use common::STDIO;
+use common::inplace::same_file;
+use crate::{BUF_SIZE, open_input};
+
+/// Identifies a share file.
+const MAGIC: &[u8; 6] = b"OTPSHR";
+const VERSION: u8 = 1;
+/// Length of the random identifier common to all shares of one split.
+const SET_ID_LEN: usize = 16;
+/// Magic, version, set identifier, share number, share count and the
+/// big-endian length of the secret.
+const HEADER_LEN: usize = MAGIC.len() + 1 + SET_ID_LEN + 1 + 1 + 8;
+
+struct Header {
+    set_id: [u8; SET_ID_LEN],
+    index: u8,
+    count: u8,
+    len: u64,
+}
+
+impl Header {
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes[MAGIC.len()] = VERSION;
+        let fields = &mut bytes[MAGIC.len() + 1..];
+        fields[..SET_ID_LEN].copy_from_slice(&self.set_id);
+        fields[SET_ID_LEN] = self.index;
+        fields[SET_ID_LEN + 1] = self.count;
+        fields[SET_ID_LEN + 2..].copy_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+
+    fn read(input: &mut impl Read, name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut bytes = [0u8; HEADER_LEN];
+        input.read_exact(&mut bytes).map_err(|_| format!("{} is not an OTP share.", name))?;
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Err(format!("{} is not an OTP share.", name).into());
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(format!("{} has unsupported share version {}.", name, bytes[MAGIC.len()]).into());
+        }
+        let fields = &bytes[MAGIC.len() + 1..];
+        let mut set_id = [0u8; SET_ID_LEN];
+        set_id.copy_from_slice(&fields[..SET_ID_LEN]);
+        let header = Header {
+            set_id,
+            index: fields[SET_ID_LEN],
+            count: fields[SET_ID_LEN + 1],
+            len: u64::from_be_bytes(fields[SET_ID_LEN + 2..].try_into().unwrap()),
+        };
+        if header.count < 2 || header.index == 0 || header.index > header.count {
+            return Err(format!("{} has an invalid share header.", name).into());
+        }
+        Ok(header)
+    }
+}
+
+/// Path of share `index` of `count` for the output prefix `prefix`.
+fn share_path(prefix: &str, index: u8, count: u8) -> PathBuf {
+    PathBuf::from(format!("{}.{}of{}.share", prefix, index, count))
+}
+
+/// Splits `input_path` into `count` shares named after `prefix`: `count - 1`
+/// of random bytes from the OS, and one holding the XOR of the input with all
+/// of them. All shares are needed to rebuild the input; any fewer reveal
+/// nothing about it but its length.
+pub fn split(input_path: &str, count: u8, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (input, len) = open_input(input_path)?;
+    let mut set_id = [0u8; SET_ID_LEN];
+    OsRng.fill_bytes(&mut set_id);
+
+    let paths: Vec<PathBuf> = (1..=count).map(|index| share_path(prefix, index, count)).collect();
+    let mut created = Vec::new();
+    let written = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut outputs = Vec::new();
+        for (path, index) in paths.iter().zip(1..=count) {
+            let file = OpenOptions::new().write(true).create_new(true).open(path)
+                .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+            created.push(path.clone());
+            let mut writer = BufWriter::with_capacity(BUF_SIZE, file);
+            writer.write_all(&Header { set_id, index, count, len }.to_bytes())?;
+            outputs.push(writer);
+        }
+
+        let mut input = BufReader::with_capacity(BUF_SIZE, input);
+        let mut data = vec![0u8; BUF_SIZE];
+        let mut random = vec![0u8; BUF_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(BUF_SIZE as u64) as usize;
+            input.read_exact(&mut data[..n]).map_err(|e| format!("Cannot read input: {}", e))?;
+            let (last, pads) = outputs.split_last_mut().unwrap();
+            for output in pads {
+                OsRng.fill_bytes(&mut random[..n]);
+                output.write_all(&random[..n])?;
+                data[..n].iter_mut().zip(&random[..n]).for_each(|(byte, &r)| *byte ^= r);
+            }
+            last.write_all(&data[..n])?;
+            remaining -= n as u64;
+        }
+        for output in outputs {
+            output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = written {
+        for path in &created {
+            let _ = fs::remove_file(path);
+        }
+        return Err(e);
+    }
+
+    for path in &paths {
+        println!("{}", path.display());
+    }
+    eprintln!("Split {} bytes into {} shares; all of them are needed to rebuild it.", len, count);
+    Ok(())
+}
+
+/// Rebuilds the secret from `shares` into `output_path` by XORing them
+/// together. Every share of the set has to be given, each exactly once; the
+/// headers are checked before anything is written.
+pub fn combine(output_path: &str, shares: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut inputs = Vec::new();
+    for name in shares {
+        if output_path != STDIO && same_file(Path::new(name), Path::new(output_path))? {
+            return Err(format!("{} is both a share and the output.", name).into());
+        }
+        let file = File::open(name).map_err(|e| format!("Cannot open {}: {}", name, e))?;
+        let metadata = file.metadata()?;
+        let (file_type, size) = (metadata.file_type(), metadata.len());
+        let mut reader = BufReader::with_capacity(BUF_SIZE, file);
+        let header = Header::read(&mut reader, name)?;
+        // A pipe reports no size, so its length is only checked as it is read.
+        if file_type.is_file() && size.checked_sub(HEADER_LEN as u64) != Some(header.len) {
+            return Err(format!("{} should hold {} bytes after its header but holds {}; it is truncated or damaged.",
+                               name, header.len, size.saturating_sub(HEADER_LEN as u64)).into());
+        }
+        inputs.push((name, header, reader));
+    }
+
+    let (first_name, first, _) = &inputs[0];
+    let (set_id, count, len) = (first.set_id, first.count, first.len);
+    let mut seen = vec![None; count as usize];
+    for (name, header, _) in &inputs {
+        if header.set_id != set_id || header.count != count || header.len != len {
+            return Err(format!("{} and {} are shares of different secrets.", first_name, name).into());
+        }
+        if let Some(other) = seen[header.index as usize - 1].replace(name) {
+            return Err(format!("{} and {} are both share {} of {}.", other, name, header.index, count).into());
+        }
+    }
+    let missing: Vec<String> = seen.iter().zip(1..).filter(|(name, _)| name.is_none()).map(|(_, i)| i.to_string()).collect();
+    if !missing.is_empty() {
+        let noun = if missing.len() == 1 { "share" } else { "shares" };
+        return Err(format!("Missing {} {} of {}; all {} shares are needed.", noun, missing.join(", "), count, count).into());
+    }
+
+    let output: Box<dyn Write> = if output_path == STDIO {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(output_path).map_err(|e| format!("Cannot create {}: {}", output_path, e))?)
+    };
+    let mut output = BufWriter::with_capacity(BUF_SIZE, output);
+    let written = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let damaged = |name: &str| format!("{} should hold {} bytes after its header; it is truncated or damaged.", name, len);
+        let mut data = vec![0u8; BUF_SIZE];
+        let mut share = vec![0u8; BUF_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(BUF_SIZE as u64) as usize;
+            data[..n].fill(0);
+            for (name, _, reader) in &mut inputs {
+                reader.read_exact(&mut share[..n]).map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => damaged(name),
+                    _ => format!("Cannot read {}: {}", name, e),
+                })?;
+                data[..n].iter_mut().zip(&share[..n]).for_each(|(byte, &s)| *byte ^= s);
+            }
+            output.write_all(&data[..n])?;
+            remaining -= n as u64;
+        }
+        for (name, _, reader) in &mut inputs {
+            if reader.read(&mut share[..1])? != 0 {
+                return Err(damaged(name).into());
+            }
+        }
+        output.flush()?;
+        Ok(())
+    })();
+    if written.is_err() && output_path != STDIO {
+        drop(output);
+        let _ = fs::remove_file(output_path);
+    }
+    written
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+/// Length of the key fingerprint stored in the header.
+pub const KEY_ID_LEN: usize = 8;
+
+/// Extension of key files in a keyring directory.
+const KEY_EXTENSION: &str = "key";
+
+/// A 32-byte key and the name it is stored under.
+pub struct NamedKey {
+    pub name: String,
+    pub key: [u8; 32],
+}
+
+/// Fingerprint of a key: HMAC-SHA256 keyed with the key itself over a fixed
+/// label, truncated to `KEY_ID_LEN` bytes. It tells keys apart without
+/// revealing anything about them.
+pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"chakey key id v1");
+    let digest = mac.finalize().into_bytes();
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&digest[..KEY_ID_LEN]);
+    id
+}
+
+/// Hex form of a fingerprint, for messages and `-L`.
+pub fn format_id(id: &[u8; KEY_ID_LEN]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads a 32-byte key from `path`.
+pub fn read_key(path: &Path) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let key_data = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if key_data.len() != 32 {
+        return Err(format!("Invalid key length in {}; expected 32 bytes.", path.display()).into());
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&key_data);
+    Ok(key)
+}
+
+/// Key names become file names, so they are restricted to letters, digits,
+/// '-', '_' and '.', and may not start with '.'.
+fn check_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(format!("Invalid key name {:?}; use letters, digits, '-', '_' and '.'.", name).into());
+    }
+    Ok(())
+}
+
+/// A directory of named keys, each stored as `<name>.key`.
+pub struct Keyring {
+    dir: PathBuf,
+    keys: Vec<NamedKey>,
+}
+
+impl Keyring {
+    /// Loads every `*.key` file in `dir`, sorted by name.
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("Cannot open keyring {}: {}", dir.display(), e))?;
+        let mut keys = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != KEY_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            keys.push(NamedKey { name: name.to_string(), key: read_key(&path)? });
+        }
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Keyring { dir: dir.to_path_buf(), keys })
+    }
+
+    /// Treats the single key file at `path` as a keyring, for use without a
+    /// keyring directory.
+    pub fn from_key_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(Keyring { dir: path.to_path_buf(), keys: vec![NamedKey { name, key: read_key(path)? }] })
+    }
+
+    /// The keyring directory, or the key file it was loaded from.
+    pub fn location(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn keys(&self) -> &[NamedKey] {
+        &self.keys
+    }
+
+    /// The key called `name`, or the only key when no name is given.
+    pub fn select(&self, name: Option<&str>) -> Result<&NamedKey, Box<dyn std::error::Error>> {
+        match name {
+            Some(name) => self.keys.iter().find(|k| k.name == name)
+                .ok_or_else(|| format!("No key named {:?} in keyring {}.", name, self.dir.display()).into()),
+            None => match self.keys.as_slice() {
+                [only] => Ok(only),
+                [] => Err(format!("Keyring {} holds no keys; create one with -G <name>.", self.dir.display()).into()),
+                _ => Err(format!("Keyring {} holds several keys; choose one with --key <name>.", self.dir.display()).into()),
+            },
+        }
+    }
+
+    /// The key whose fingerprint is `id`.
+    pub fn find(&self, id: &[u8; KEY_ID_LEN]) -> Option<&NamedKey> {
+        self.keys.iter().find(|k| key_id(&k.key) == *id)
+    }
+
+    /// Creates a new random key called `name`. Existing keys are never replaced.
+    pub fn generate(dir: &Path, name: &str) -> Result<NamedKey, Box<dyn std::error::Error>> {
+        check_name(name)?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.{}", name, KEY_EXTENSION));
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+        file.write_all(&key)?;
+        file.sync_all()?;
+        Ok(NamedKey { name: name.to_string(), key })
+    }
+}
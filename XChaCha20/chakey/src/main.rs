@@ -1,78 +1,361 @@
+mod keyring;
+
 use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
 use chacha20poly1305::aead::{Aead, KeyInit, OsRng, generic_array::GenericArray};
 use chacha20poly1305::XChaCha20Poly1305;
 use rand::RngCore;
+use common::{STDIO, archive, create_output, open_input};
+use common::inplace::{PendingOutput, TempFile, refuse_same_file, same_contents};
+use keyring::{KEY_ID_LEN, Keyring, NamedKey, format_id, key_id};
+use common::stream::{DecryptReader, EncryptWriter, NONCE_PREFIX_LEN};
+use common::verify::{self, Verdict};
+
+/// Key file used when no keyring is given.
+const DEFAULT_KEY_FILE: &str = "key.key";
+
+/// Environment variable naming the keyring directory when `--keyring` is not given.
+const KEYRING_ENV: &str = "CHAKEY_KEYRING";
+
+/// Loads the keyring directory given with `--keyring` or `CHAKEY_KEYRING`,
+/// or else the single key in "key.key".
+fn load_keys(keyring: Option<&str>) -> Result<Keyring, Box<dyn std::error::Error>> {
+    match keyring.map(String::from).or_else(|| env::var(KEYRING_ENV).ok()) {
+        Some(dir) => Keyring::open(Path::new(&dir)),
+        None => Keyring::from_key_file(Path::new(DEFAULT_KEY_FILE)),
+    }
+}
+
+/// Magic bytes at the start of files with a header; they identify the tool
+/// and its key mode (key file). Files without them are in the original
+/// format: [nonce (24)] || [ciphertext], which is only read with `--legacy`.
+const MAGIC: &[u8; 6] = b"CHAKEY";
+
+/// Magic bytes of files from the other tools in this repository, so that
+/// decryption can say which tool to use instead of failing to authenticate.
+const FOREIGN_MAGICS: [(&[u8], &str); 3] = [
+    (b"CHAPAS", "This file was encrypted with a password; use chapas to decrypt it."),
+    (b"SECUREENC", "This file was encrypted with the AES tool; use aes to decrypt it."),
+    (b"SECUREENS", "This file was encrypted with the AES tool; use aes to decrypt it."),
+];
+
+/// Header format version.
+const VERSION: u8 = 1;
+
+/// The plaintext is the contents of a single file.
+const KIND_FILE: u8 = 1;
+
+/// The plaintext is a directory tree in the format of `archive::pack`.
+const KIND_ARCHIVE: u8 = 2;
+
+/// Length of the magic, version and kind.
+const PREFIX_LEN: usize = 6 + 1 + 1;
+
+/// Length of the header fields before the nonce:
+/// [magic (6)] || [version (1)] || [kind (1)] || [key ID (8)]
+/// They are authenticated as associated data, so neither the kind nor the
+/// key ID can be altered.
+const HEADER_LEN: usize = PREFIX_LEN + KEY_ID_LEN;
+
+/// Encrypts the file or directory at `input_path` and writes the result to `output_path`.
+/// Either path may be "-" for standard input or output.
+/// Output format: [magic (6)] || [version (1)] || [kind (1)] || [key ID (8)] || [nonce prefix (19)]
+/// || [chunks], where each chunk of `common::stream::CHUNK_SIZE` plaintext bytes is
+/// sealed separately, so memory use does not depend on the file size.
+/// Returns the key that was used.
+fn encrypt_file(input_path: &str, output_path: &str, keys: &Keyring, key_name: Option<&str>) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    refuse_same_file(input_path, output_path)?;
+    let is_dir = input_path != STDIO && Path::new(input_path).is_dir();
+    let input = if is_dir { None } else { Some(BufReader::new(open_input(input_path)?)) };
+    let key = &keys.select(key_name)?.key;
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    header.push(if is_dir { KIND_ARCHIVE } else { KIND_FILE });
+    header.extend_from_slice(&key_id(key));
+    let mut out_file = BufWriter::new(create_output(output_path)?);
+    out_file.write_all(&header)?;
+    out_file.write_all(&nonce_prefix)?;
 
-/// Reads a 32‑byte key from the "key.key" file.
-fn load_key_from_file() -> Result<[u8; 32], Box<dyn std::error::Error>> {
-    let key_data = fs::read("key.key")?;
-    if key_data.len() != 32 {
-        return Err("Invalid key length in key.key; expected 32 bytes.".into());
+    let mut writer = EncryptWriter::new(out_file, key, &nonce_prefix, header);
+    match input {
+        Some(mut input) => { io::copy(&mut input, &mut writer)?; }
+        None => archive::pack(Path::new(input_path), &mut writer)?,
     }
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&key_data);
-    Ok(key)
+    writer.finish()?;
+    Ok(*key)
 }
 
-/// Encrypts the file at `input_path` and writes the result to `output_path`.
-/// Output format: [nonce (24 bytes)] || [ciphertext]
-fn encrypt_file(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let plaintext = fs::read(input_path)?;
-    let key = load_key_from_file()?;
-    let mut nonce = [0u8; 24];
-    OsRng.fill_bytes(&mut nonce);
-    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), plaintext.as_ref())
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    let mut output = nonce.to_vec();
-    output.extend(ciphertext);
-    fs::write(output_path, output)?;
+/// Checks that the file at `encrypted_path`, just written by `encrypt_file`,
+/// decrypts with `key` to exactly the contents of `original_path`.
+fn verify_encrypted(encrypted_path: &Path, key: &[u8; 32], original_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = BufReader::new(fs::File::open(encrypted_path)?);
+    let mut header = vec![0u8; HEADER_LEN];
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    input.read_exact(&mut header)?;
+    input.read_exact(&mut nonce_prefix)?;
+    let reader = DecryptReader::new(input, key, &nonce_prefix, header);
+    if !same_contents(reader, BufReader::new(fs::File::open(original_path)?))? {
+        return Err("Verification of the encrypted file failed; the original was left unchanged.".into());
+    }
+    Ok(())
+}
+
+/// Replaces the regular file at `path` with its encryption. The ciphertext is
+/// written to a temporary file next to it, synced and decrypted again for
+/// comparison with the original, which is only then replaced by a rename.
+fn encrypt_in_place(path: &str, keys: &Keyring, key_name: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    let key = encrypt_file(path, temp_path, keys, key_name)?;
+    verify_encrypted(temp.path(), &key, path)?;
+    temp.copy_permissions()?;
+    temp.commit()?;
+    Ok(())
+}
+
+/// Replaces the encrypted file at `path` with its plaintext. The plaintext is
+/// written to a temporary file next to it, and the original is only replaced
+/// once all of it has been authenticated and the file has been synced.
+/// Directory archives cannot replace a file and are refused.
+fn decrypt_in_place(path: &str, keys: &Keyring, legacy: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut prefix = Vec::with_capacity(PREFIX_LEN);
+    fs::File::open(path)?.take(PREFIX_LEN as u64).read_to_end(&mut prefix)?;
+    if prefix.starts_with(MAGIC) && prefix.get(7) == Some(&KIND_ARCHIVE) {
+        return Err("This file holds a directory archive, which cannot be decrypted in place.".into());
+    }
+
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    decrypt_file(path, temp_path, keys, legacy)?;
+    temp.copy_permissions()?;
+    temp.commit()?;
     Ok(())
 }
 
+/// A file's header fields and the payload that follows them.
+struct Envelope<R: Read> {
+    kind: u8,
+    /// The header bytes, which are the associated data of the payload; empty
+    /// for files in the original headerless format.
+    header: Vec<u8>,
+    payload: io::Chain<io::Cursor<Vec<u8>>, R>,
+}
+
+/// Reads and checks the header at the start of `input`. Files in the
+/// original [nonce (24 bytes)] || [ciphertext] format have nothing to
+/// identify them, so they are only accepted when `legacy` is set.
+fn read_envelope<R: Read>(mut input: R, legacy: bool) -> Result<Envelope<R>, Box<dyn std::error::Error>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    (&mut input).take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if let Some((_, message)) = FOREIGN_MAGICS.iter().find(|(magic, _)| header.starts_with(magic)) {
+        return Err((*message).into());
+    }
+    let (header_len, kind) = if header.starts_with(MAGIC) && header.len() >= PREFIX_LEN {
+        if header[6] != VERSION {
+            return Err(format!("Unsupported format version {}.", header[6]).into());
+        }
+        if header.len() < HEADER_LEN {
+            return Err("Input file too short to contain a valid header.".into());
+        }
+        (HEADER_LEN, header[7])
+    } else if !legacy {
+        return Err("This is not a chakey file. If it was encrypted by a version of chakey without a file header, pass --legacy.".into());
+    } else {
+        (0, KIND_FILE)
+    };
+    if kind != KIND_FILE && kind != KIND_ARCHIVE {
+        return Err(format!("Unsupported content kind {}.", kind).into());
+    }
+
+    // Anything read past the header belongs to the payload.
+    let rest = header.split_off(header_len);
+    Ok(Envelope { kind, header, payload: io::Cursor::new(rest).chain(input) })
+}
+
+impl<R: Read> Envelope<R> {
+    /// The key whose fingerprint is in the header, or every available key
+    /// for headerless files.
+    fn candidates<'a>(&self, keys: &'a Keyring) -> Result<Vec<&'a NamedKey>, Box<dyn std::error::Error>> {
+        match self.header.get(PREFIX_LEN..HEADER_LEN) {
+            Some(id) => {
+                let id: [u8; KEY_ID_LEN] = id.try_into().expect("slice has the key ID length");
+                let key = keys.find(&id).ok_or_else(|| format!("No key in {} matches this file (key ID {}).",
+                                                               keys.location().display(), format_id(&id)))?;
+                Ok(vec![key])
+            }
+            None => Ok(keys.keys().iter().collect()),
+        }
+    }
+
+    /// Decrypts the payload with the first of `candidates` that authenticates it.
+    /// Headerless files, which sealed the whole file at once, are decrypted
+    /// in memory.
+    fn into_reader(mut self, candidates: &[&NamedKey]) -> Result<Box<dyn Read>, Box<dyn std::error::Error>>
+    where
+        R: 'static,
+    {
+        if !self.header.is_empty() {
+            let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+            self.payload.read_exact(&mut nonce_prefix).map_err(|_| "Input file too short to contain a valid header.")?;
+            return Ok(Box::new(DecryptReader::new(self.payload, &candidates[0].key, &nonce_prefix, self.header)));
+        }
+        // The original format can only be authenticated as a whole.
+        let mut data = Vec::new();
+        self.payload.read_to_end(&mut data)?;
+        if data.len() < 24 {
+            return Err("Input file too short to contain a valid nonce.".into());
+        }
+        let (nonce, ciphertext) = data.split_at(24);
+        let plaintext = candidates.iter().find_map(|named| {
+            let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&named.key));
+            cipher.decrypt(GenericArray::from_slice(nonce), ciphertext).ok()
+        }).ok_or("Decryption failed: wrong key, or the file is corrupted or has been modified.")?;
+        Ok(Box::new(io::Cursor::new(plaintext)))
+    }
+}
+
 /// Decrypts the file at `input_path` and writes the plaintext to `output_path`.
-/// Expects file format: [nonce (24 bytes)] || [ciphertext]
-fn decrypt_file(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let data = fs::read(input_path)?;
-    if data.len() < 24 {
-        return Err("Input file too short to contain a valid nonce.".into());
+/// For a directory archive, `output_path` is the directory to create.
+/// The key is the one whose fingerprint is in the header; headerless files
+/// are tried with each available key in turn.
+fn decrypt_file(input_path: &str, output_path: &str, keys: &Keyring, legacy: bool) -> Result<(), Box<dyn std::error::Error>> {
+    refuse_same_file(input_path, output_path)?;
+    let envelope = read_envelope(BufReader::new(open_input(input_path)?), legacy)?;
+    let kind = envelope.kind;
+    if kind == KIND_ARCHIVE && output_path == STDIO {
+        return Err("This file holds a directory archive, which cannot be written to standard output.".into());
+    }
+    let candidates = envelope.candidates(keys)?;
+    let mut reader = envelope.into_reader(&candidates)?;
+
+    if kind == KIND_ARCHIVE {
+        // The tree is extracted into a new directory, which is removed again on failure.
+        archive::unpack(&mut reader, Path::new(output_path)).map_err(|e| e.to_string())?;
+    } else {
+        // The file only replaces an existing one once every chunk has authenticated.
+        let mut out_file = PendingOutput::create(output_path, false)?;
+        io::copy(&mut reader, &mut out_file).map_err(|e| e.to_string())?;
+        out_file.commit()?;
     }
-    let nonce = &data[..24];
-    let ciphertext = &data[24..];
-    let key = load_key_from_file()?;
-    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let plaintext = cipher.decrypt(GenericArray::from_slice(nonce), ciphertext)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    fs::write(output_path, plaintext)?;
     Ok(())
 }
 
+/// Authenticates all of the file at `path` without writing any plaintext.
+/// A fingerprint without a matching key means a wrong key; a payload that
+/// fails with the matching key means the file is damaged. Headerless files
+/// cannot tell the two apart.
+fn verify_file(path: &Path, keys: &Keyring, legacy: bool) -> Verdict {
+    let mut input = match fs::File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return Verdict::Failed(e.to_string()),
+    };
+    // A bad header is damage if the file is ours, and otherwise not our business.
+    let ours = input.fill_buf().is_ok_and(|buf| buf.starts_with(MAGIC));
+    let envelope = match read_envelope(input, legacy) {
+        Ok(envelope) => envelope,
+        Err(e) if ours => return Verdict::Corrupt(e.to_string()),
+        Err(e) => return Verdict::Skipped(e.to_string()),
+    };
+    let identified = !envelope.header.is_empty();
+    let candidates = match envelope.candidates(keys) {
+        Ok(candidates) => candidates,
+        Err(e) => return Verdict::WrongKey(e.to_string()),
+    };
+    let result = envelope.into_reader(&candidates)
+        .and_then(|mut reader| Ok(io::copy(&mut reader, &mut io::sink())?));
+    match result {
+        Ok(_) => Verdict::Ok,
+        Err(e) if identified => Verdict::Corrupt(e.to_string()),
+        Err(e) => Verdict::Failed(e.to_string()),
+    }
+}
+
+/// Prints the name and fingerprint of each available key.
+fn list_keys(keys: &Keyring) {
+    for named in keys.keys() {
+        println!("{}  {}", format_id(&key_id(&named.key)), named.name);
+    }
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("Error: expected usage: {} [-E|-D] <input> <output>   (- for standard input or output)", program);
+    eprintln!("       {} -D <input> <output> --legacy   (file from a version of chakey without a file header)", program);
+    eprintln!("       {} [-E|-D] --in-place <file>      (replace the file, once the result is complete)", program);
+    eprintln!("       {} -V <file or directory>         (check that files authenticate, without writing plaintext)", program);
+    eprintln!("       {} -G <name> --keyring <dir>      (add a new random key to a keyring)", program);
+    eprintln!("       {} -L [--keyring <dir>]           (list key fingerprints)", program);
+    eprintln!("Options: --keyring <dir> (default ${} or {}), --key <name> (key to encrypt with)", KEYRING_ENV, DEFAULT_KEY_FILE);
+    std::process::exit(1);
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Collect command-line arguments.
     let args: Vec<String> = env::args().collect();
+    let flag = args.get(1).map(String::as_str).unwrap_or("");
 
-    // Expect exactly 4 arguments: program, flag, input, output.
-    if args.len() != 4 {
-        eprintln!("Error: expected usage: {} [-E|-D] <input> <output>", args[0]);
-        std::process::exit(1);
+    // Split the remaining arguments into paths and options.
+    let mut paths = Vec::new();
+    let mut keyring = None;
+    let mut key_name = None;
+    let mut legacy = false;
+    let mut in_place = false;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--keyring" => match rest.next() {
+                Some(dir) => keyring = Some(dir.as_str()),
+                None => usage(&args[0]),
+            },
+            "--key" if flag == "-E" => match rest.next() {
+                Some(name) => key_name = Some(name.as_str()),
+                None => usage(&args[0]),
+            },
+            "--legacy" if flag == "-D" || flag == "-V" => legacy = true,
+            "--in-place" if flag == "-E" || flag == "-D" => in_place = true,
+            option if option.starts_with("--") => {
+                eprintln!("Error: unknown option {}.", option);
+                usage(&args[0]);
+            }
+            path => paths.push(path),
+        }
     }
 
-    let flag = &args[1];
-    let input = &args[2];
-    let output = &args[3];
+    let valid = match flag {
+        "-G" | "-V" => paths.len() == 1,
+        "-L" => paths.is_empty(),
+        _ if in_place => paths.len() == 1 && paths[0] != STDIO,
+        _ => paths.len() == 2,
+    };
+    if !valid {
+        usage(&args[0]);
+    }
 
-    match flag.as_str() {
-        "-E" => encrypt_file(input, output)?,
-        "-D" => decrypt_file(input, output)?,
+    match flag {
+        "-E" if in_place => encrypt_in_place(paths[0], &load_keys(keyring)?, key_name)?,
+        "-D" if in_place => decrypt_in_place(paths[0], &load_keys(keyring)?, legacy)?,
+        "-E" => { encrypt_file(paths[0], paths[1], &load_keys(keyring)?, key_name)?; }
+        "-D" => decrypt_file(paths[0], paths[1], &load_keys(keyring)?, legacy)?,
+        "-V" => {
+            let keys = load_keys(keyring)?;
+            verify::run(Path::new(paths[0]), |path| verify_file(path, &keys, legacy))?;
+        }
+        "-G" => {
+            let dir = keyring.map(String::from).or_else(|| env::var(KEYRING_ENV).ok())
+                .ok_or("-G needs a keyring directory; pass --keyring <dir>.")?;
+            let named = Keyring::generate(Path::new(&dir), paths[0])?;
+            println!("{}  {}", format_id(&key_id(&named.key)), named.name);
+        }
+        "-L" => list_keys(&load_keys(keyring)?),
         _ => {
-            eprintln!("Error: Unknown flag {}. Use -E for encryption or -D for decryption.", flag);
+            eprintln!("Error: Unknown flag {}. Use -E for encryption, -D for decryption, -V to verify, -G to generate a key or -L to list keys.", flag);
             std::process::exit(1);
         }
     }
 
     Ok(())
 }
-
-
@@ -1,96 +1,782 @@
+mod password;
 
 use std::env;
-use std::fs;
-use std::io;
-use chacha20poly1305::aead::{Aead, KeyInit, OsRng, generic_array::GenericArray};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload, generic_array::GenericArray};
 use chacha20poly1305::XChaCha20Poly1305;
 use rand::RngCore;
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
 use rpassword::prompt_password;
+use common::{STDIO, archive, create_output, open_input};
+use common::inplace::{PendingOutput, TempFile, refuse_same_file, same_contents};
+use common::metadata::Metadata;
+use password::{PasswordSource, prompt_new_password};
+use common::stream::{DecryptReader, EncryptWriter, NONCE_PREFIX_LEN};
+use common::strength::{DEFAULT_MIN_SCORE, MAX_SCORE, Policy};
+use common::verify::{self, Verdict};
 
-/// Derives a 32‑byte key from the provided password and salt using Argon2.
-fn derive_key_from_password(password: &str, salt: &[u8]) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+/// Accepted ranges for the Argon2id cost parameters, both on the command line
+/// and in file headers, so a corrupted header cannot demand absurd resources.
+const MIN_MEMORY_KIB: u32 = 8192;
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_LANES: u32 = 64;
+
+/// Argon2id cost parameters.
+#[derive(Clone, Copy, PartialEq)]
+struct KdfParams {
+    memory_kib: u32,
+    iterations: u32,
+    lanes: u32,
+}
+
+impl KdfParams {
+    /// The parameters of `Argon2::default()` in argon2 0.4, which derived the
+    /// keys of headerless files. They are spelled out so that a dependency
+    /// upgrade cannot lock those files out.
+    const LEGACY: KdfParams = KdfParams { memory_kib: 4096, iterations: 3, lanes: 1 };
+
+    /// Parameters for new files unless overridden on the command line.
+    const DEFAULT: KdfParams = KdfParams { memory_kib: 65536, iterations: 3, lanes: 4 };
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !(MIN_MEMORY_KIB..=MAX_MEMORY_KIB).contains(&self.memory_kib) {
+            return Err(format!("Argon2 memory cost {} KiB is out of range ({} to {} KiB).",
+                               self.memory_kib, MIN_MEMORY_KIB, MAX_MEMORY_KIB).into());
+        }
+        if !(1..=MAX_ITERATIONS).contains(&self.iterations) {
+            return Err(format!("Argon2 iteration count {} is out of range (1 to {}).", self.iterations, MAX_ITERATIONS).into());
+        }
+        if !(1..=MAX_LANES).contains(&self.lanes) {
+            return Err(format!("Argon2 lane count {} is out of range (1 to {}).", self.lanes, MAX_LANES).into());
+        }
+        Ok(())
+    }
+}
+
+/// Argon2id parameters given on the command line; unset ones are taken from
+/// the defaults (when encrypting) or from the file (when changing the password).
+#[derive(Default)]
+struct KdfOptions {
+    memory_kib: Option<u32>,
+    iterations: Option<u32>,
+    lanes: Option<u32>,
+}
+
+impl KdfOptions {
+    fn is_empty(&self) -> bool {
+        self.memory_kib.is_none() && self.iterations.is_none() && self.lanes.is_none()
+    }
+
+    /// Fills the unset parameters from `base`. Only parameters given here are
+    /// range-checked: `base` is either a known constant or read from a header,
+    /// which has its own checks.
+    fn apply(&self, base: KdfParams) -> Result<KdfParams, Box<dyn std::error::Error>> {
+        if self.is_empty() {
+            return Ok(base);
+        }
+        let kdf = KdfParams {
+            memory_kib: self.memory_kib.unwrap_or(base.memory_kib),
+            iterations: self.iterations.unwrap_or(base.iterations),
+            lanes: self.lanes.unwrap_or(base.lanes),
+        };
+        kdf.validate()?;
+        Ok(kdf)
+    }
+}
+
+/// Derives a 32‑byte key from the provided password and salt using Argon2id
+/// with the given cost parameters.
+fn derive_key_from_password(password: &str, salt: &[u8], kdf: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
     let mut key = [0u8; 32];
-    let argon2 = Argon2::default();
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.lanes, Some(32))
+          .map_err(|e| io::Error::other(e.to_string()))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
     argon2.hash_password_into(password.as_bytes(), salt, &mut key)
-          .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+          .map_err(|e| io::Error::other(e.to_string()))?;
     Ok(key)
 }
 
+/// Password-derived keys, remembered per salt and Argon2id parameters so that
+/// a batch of files sharing a salt costs a single Argon2id run. The password
+/// itself is only read when the first key is needed.
+struct KeyCache {
+    source: PasswordSource,
+    /// Whether a prompted password must be entered twice.
+    confirm: bool,
+    /// Strength a new password must have; checked as soon as it is read.
+    policy: Option<Policy>,
+    password: Option<String>,
+    /// Salt for newly written headers; every file encrypted through this
+    /// cache shares it, while each still gets its own data key and nonces.
+    salt: [u8; 16],
+    derived: Vec<(KdfParams, [u8; 16], [u8; 32])>,
+}
+
+impl KeyCache {
+    fn new(source: PasswordSource, confirm: bool, policy: Option<Policy>) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        KeyCache { source, confirm, policy, password: None, salt, derived: Vec::new() }
+    }
+
+    /// A cache for a password that has already been read.
+    fn with_password(password: String) -> Self {
+        let mut keys = KeyCache::new(PasswordSource::Prompt, false, None);
+        keys.password = Some(password);
+        keys
+    }
+
+    /// Reads the password from its source unless that has already happened,
+    /// and checks a new one against the policy before any key is derived.
+    fn password(&mut self) -> Result<&str, Box<dyn std::error::Error>> {
+        if self.password.is_none() {
+            let password = self.source.read(self.confirm)?;
+            if let Some(policy) = &self.policy {
+                policy.check(&password)?;
+            }
+            self.password = Some(password);
+        }
+        Ok(self.password.as_deref().expect("read above"))
+    }
+
+    fn key(&mut self, salt: &[u8; 16], kdf: &KdfParams) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        if let Some((_, _, key)) = self.derived.iter().find(|(k, s, _)| k == kdf && s == salt) {
+            return Ok(*key);
+        }
+        let key = derive_key_from_password(self.password()?, salt, kdf)?;
+        self.derived.push((*kdf, *salt, key));
+        Ok(key)
+    }
+}
+
+/// Magic bytes at the start of envelope-format files; they identify the tool
+/// and its key mode (password). Files without them are in the original
+/// format: [salt (16)] || [nonce (24)] || [ciphertext], which is only read with
+/// `--legacy`.
+const MAGIC: &[u8; 6] = b"CHAPAS";
+
+/// Magic bytes of files from the other tools in this repository, so that
+/// decryption can say which tool to use instead of failing to authenticate.
+const FOREIGN_MAGICS: [(&[u8], &str); 3] = [
+    (b"CHAKEY", "This file was encrypted with a key file; use chakey to decrypt it."),
+    (b"SECUREENC", "This file was encrypted with the AES tool; use aes to decrypt it."),
+    (b"SECUREENS", "This file was encrypted with the AES tool; use aes to decrypt it."),
+];
+
+/// Envelope format version.
+const VERSION: u8 = 1;
+
+/// The plaintext holds the contents of a single file.
+const KIND_FILE: u8 = 1;
+
+/// The plaintext holds a directory tree in the format of `archive::pack`.
+const KIND_ARCHIVE: u8 = 2;
+
+/// The plaintext holds data read from standard input, without a metadata block.
+const KIND_DATA: u8 = 3;
+
+/// Size of the envelope header:
+/// [magic (6)] || [version (1)] || [kind (1)] || [memory KiB (4)] || [iterations (4)] || [lanes (4)]
+/// || [salt (16)] || [wrap nonce (24)] || [wrapped key (32 + 16)] || [nonce prefix (19)]
+/// Integers are big-endian. The payload that follows is a sequence of
+/// chunks, see `common::stream`.
+///
+/// The ciphertext is authenticated against the magic, version and kind
+/// (`PREFIX_LEN` bytes), and the wrapped key additionally against the
+/// Argon2id parameters and salt, so no header field can be altered unnoticed.
+/// The fields a password change rewrites are not part of the payload's
+/// associated data.
+const HEADER_LEN: usize = 6 + 1 + 1 + 12 + 16 + 24 + 48 + NONCE_PREFIX_LEN;
+
+/// Length of the magic, version and kind.
+const PREFIX_LEN: usize = 6 + 1 + 1;
+
+/// Envelope header. The payload is encrypted with a random data key, which
+/// is stored wrapped under the password-derived key, so changing the password
+/// only rewrites this header.
+struct Header {
+    kind: u8,
+    kdf: KdfParams,
+    salt: [u8; 16],
+    wrap_nonce: [u8; 24],
+    wrapped_key: [u8; 48],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl Header {
+    fn new(kind: u8, kdf: KdfParams) -> Self {
+        Header { kind, kdf, salt: [0u8; 16], wrap_nonce: [0u8; 24], wrapped_key: [0u8; 48], nonce_prefix: [0u8; NONCE_PREFIX_LEN] }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(self.kind);
+        out.extend_from_slice(&self.kdf.memory_kib.to_be_bytes());
+        out.extend_from_slice(&self.kdf.iterations.to_be_bytes());
+        out.extend_from_slice(&self.kdf.lanes.to_be_bytes());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.wrap_nonce);
+        out.extend_from_slice(&self.wrapped_key);
+        out.extend_from_slice(&self.nonce_prefix);
+        out
+    }
+
+    /// Associated data of the payload: the magic, version and kind.
+    fn payload_aad(&self) -> Vec<u8> {
+        self.encode()[..PREFIX_LEN].to_vec()
+    }
+
+    /// Associated data of the wrapped key: the payload's plus the Argon2id
+    /// parameters and salt.
+    fn key_aad(&self) -> Vec<u8> {
+        self.encode()[..PREFIX_LEN + 12 + 16].to_vec()
+    }
+
+    /// Parses the header at the start of `data`, or returns `None` for files
+    /// in the original headerless format. Files from the other tools are
+    /// rejected with a message naming the right one.
+    fn parse(data: &[u8]) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !data.starts_with(MAGIC) {
+            if let Some((_, message)) = FOREIGN_MAGICS.iter().find(|(magic, _)| data.starts_with(magic)) {
+                return Err((*message).into());
+            }
+            return Ok(None);
+        }
+        if data.len() < 7 {
+            return Err("Input file too short to contain a valid header.".into());
+        }
+        if data[6] != VERSION {
+            return Err(format!("Unsupported format version {}.", data[6]).into());
+        }
+        if data.len() < HEADER_LEN {
+            return Err("Input file too short to contain a valid header.".into());
+        }
+        let field = |i: usize| u32::from_be_bytes(data[8 + 4 * i..12 + 4 * i].try_into().unwrap());
+        let mut header = Header::new(data[7], KdfParams { memory_kib: field(0), iterations: field(1), lanes: field(2) });
+        if !(KIND_FILE..=KIND_DATA).contains(&header.kind) {
+            return Err(format!("Unsupported content kind {}.", header.kind).into());
+        }
+        header.kdf.validate()?;
+        let pos = PREFIX_LEN + 12;
+        header.salt.copy_from_slice(&data[pos..pos + 16]);
+        header.wrap_nonce.copy_from_slice(&data[pos + 16..pos + 40]);
+        header.wrapped_key.copy_from_slice(&data[pos + 40..pos + 88]);
+        header.nonce_prefix.copy_from_slice(&data[pos + 88..HEADER_LEN]);
+        Ok(Some(header))
+    }
+
+    /// Wraps `data_key` under the password with the cache's salt and a fresh
+    /// nonce, using the header's Argon2id parameters.
+    fn wrap_key(&mut self, keys: &mut KeyCache, data_key: &[u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        self.salt = keys.salt;
+        OsRng.fill_bytes(&mut self.wrap_nonce);
+        let key = keys.key(&self.salt, &self.kdf)?;
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        let aad = self.key_aad();
+        let wrapped = cipher.encrypt(GenericArray::from_slice(&self.wrap_nonce), Payload { msg: data_key, aad: &aad })
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.wrapped_key.copy_from_slice(&wrapped);
+        Ok(())
+    }
+
+    /// Recovers the data key, failing if the password is wrong.
+    fn unwrap_key(&self, keys: &mut KeyCache) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let key = keys.key(&self.salt, &self.kdf)?;
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        let aad = self.key_aad();
+        let data_key = cipher.decrypt(GenericArray::from_slice(&self.wrap_nonce), Payload { msg: &self.wrapped_key, aad: &aad })
+            .map_err(|_| "Incorrect password or corrupted header.")?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&data_key);
+        Ok(key)
+    }
+}
+
 /// Encrypts the file at `input_path` and writes the result to `output_path`.
-/// Output format: [salt (16 bytes)] || [nonce (24 bytes)] || [ciphertext]
-fn encrypt_file(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let plaintext = fs::read(input_path)?;
-    
-    // Ask for password twice to verify it.
-    let password1 = prompt_password("Enter password: ")?;
-    let password2 = prompt_password("Re-enter password: ")?;
-    if password1 != password2 {
-        eprintln!("Error: Passwords do not match.");
-        std::process::exit(1);
-    }
-    let password = password1;
-    
-    let mut salt = [0u8; 16];
-    OsRng.fill_bytes(&mut salt);
-    let key = derive_key_from_password(&password, &salt)?;
-    
-    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let mut nonce = [0u8; 24];
-    OsRng.fill_bytes(&mut nonce);
-    let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), plaintext.as_ref())
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    
-    let mut output = salt.to_vec();
-    output.extend(&nonce);
-    output.extend(ciphertext);
-    fs::write(output_path, output)?;
+/// Output format: [header] || [chunks], see `Header` and `common::stream`. The
+/// plaintext is the file's metadata block followed by its contents, or by an
+/// archive of the whole tree when `input_path` is a directory. Data from
+/// standard input ("-") is stored without metadata. Memory use does not
+/// depend on the size of the input. Returns the data key, for verification.
+fn encrypt_file(input_path: &str, output_path: &str, label: Option<&str>, kdf: KdfParams, keys: &mut KeyCache) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    refuse_same_file(input_path, output_path)?;
+    let is_dir = input_path != STDIO && Path::new(input_path).is_dir();
+    let (kind, metadata) = if input_path == STDIO {
+        if label.is_some() {
+            return Err("--label cannot be used when encrypting standard input.".into());
+        }
+        (KIND_DATA, None)
+    } else {
+        let kind = if is_dir { KIND_ARCHIVE } else { KIND_FILE };
+        (kind, Some(Metadata::from_file(input_path, label)?))
+    };
+    let input = if is_dir { None } else { Some(BufReader::new(open_input(input_path)?)) };
+
+    let mut data_key = [0u8; 32];
+    OsRng.fill_bytes(&mut data_key);
+    let mut header = Header::new(kind, kdf);
+    header.wrap_key(keys, &data_key)?;
+    OsRng.fill_bytes(&mut header.nonce_prefix);
+
+    let mut out_file = BufWriter::new(create_output(output_path)?);
+    out_file.write_all(&header.encode())?;
+    let mut writer = EncryptWriter::new(out_file, &data_key, &header.nonce_prefix, header.payload_aad());
+    if let Some(metadata) = &metadata {
+        writer.write_all(&metadata.encode()?)?;
+    }
+    match input {
+        Some(mut input) => { io::copy(&mut input, &mut writer)?; }
+        None => archive::pack(Path::new(input_path), &mut writer)?,
+    }
+    writer.finish()?;
+    Ok(data_key)
+}
+
+/// Checks that the file at `encrypted_path`, just written by `encrypt_file`,
+/// decrypts with `data_key` to exactly the contents of `original_path`.
+fn verify_encrypted(encrypted_path: &Path, data_key: &[u8; 32], original_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = BufReader::new(fs::File::open(encrypted_path)?);
+    let mut prefix = Vec::with_capacity(HEADER_LEN);
+    (&mut input).take(HEADER_LEN as u64).read_to_end(&mut prefix)?;
+    let header = Header::parse(&prefix)?.ok_or("Verification of the encrypted file failed: no header.")?;
+    let rest = prefix.split_off(HEADER_LEN);
+    let mut reader = DecryptReader::new(io::Cursor::new(rest).chain(input), data_key, &header.nonce_prefix, header.payload_aad());
+    Metadata::read_from(&mut reader)?;
+    if !same_contents(reader, BufReader::new(fs::File::open(original_path)?))? {
+        return Err("Verification of the encrypted file failed; the original was left unchanged.".into());
+    }
     Ok(())
 }
 
-/// Decrypts the file at `input_path` and writes the plaintext to `output_path`.
-/// Expects file format: [salt (16 bytes)] || [nonce (24 bytes)] || [ciphertext]
-fn decrypt_file(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let data = fs::read(input_path)?;
+/// Replaces the regular file at `path` with its encryption. The ciphertext is
+/// written to a temporary file next to it, synced and decrypted again for
+/// comparison with the original, which is only then replaced by a rename.
+fn encrypt_in_place(path: &str, label: Option<&str>, kdf: KdfParams, keys: &mut KeyCache) -> Result<(), Box<dyn std::error::Error>> {
+    // Ask for the password before the temporary file exists.
+    keys.password()?;
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    let data_key = encrypt_file(path, temp_path, label, kdf, keys)?;
+    verify_encrypted(temp.path(), &data_key, path)?;
+    temp.copy_permissions()?;
+    temp.commit()?;
+    Ok(())
+}
+
+/// Replaces the encrypted file at `path` with its plaintext. The plaintext is
+/// written to a temporary file next to it, and the original is only replaced
+/// once all of it has been authenticated and the file has been synced. The
+/// file keeps its permissions unless `action` restores the stored ones.
+/// Directory archives cannot replace a file and are refused.
+fn decrypt_in_place(path: &str, action: MetadataAction, legacy: bool, keys: &mut KeyCache) -> Result<(), Box<dyn std::error::Error>> {
+    let mut prefix = Vec::with_capacity(HEADER_LEN);
+    fs::File::open(path)?.take(HEADER_LEN as u64).read_to_end(&mut prefix)?;
+    if Header::parse(&prefix)?.is_some_and(|header| header.kind == KIND_ARCHIVE) {
+        return Err("This file holds a directory archive, which cannot be decrypted in place.".into());
+    }
+
+    keys.password()?;
+    let temp = TempFile::create(Path::new(path))?;
+    let temp_path = temp.path().to_str().ok_or("Temporary file name is not valid UTF-8.")?;
+    decrypt_file(path, Some(temp_path), action, legacy, keys)?;
+    if action != MetadataAction::Restore {
+        temp.copy_permissions()?;
+    }
+    temp.commit()?;
+    Ok(())
+}
+
+/// What `-D` does with the metadata stored in the file.
+#[derive(Clone, Copy, PartialEq)]
+enum MetadataAction {
+    /// Write the contents only.
+    Ignore,
+    /// Write the contents, then apply the stored mode and mtime.
+    Restore,
+    /// Print the metadata without writing the contents.
+    Show,
+}
+
+/// Copies decrypted data from `reader` to `path`. A name taken from the file
+/// (`create_new`) must not replace an existing file. The file only takes its
+/// place once every chunk has authenticated, so a failure leaves an existing
+/// file untouched.
+fn write_plaintext<R: Read>(reader: &mut R, path: &str, create_new: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out_file = PendingOutput::create(path, create_new)?;
+    io::copy(reader, &mut out_file).map_err(|e| e.to_string())?;
+    out_file.commit()?;
+    Ok(())
+}
+
+/// Decrypts `payload`, the part of a file after `header`, chunk by chunk, or
+/// a whole file in the original format when there is no header. The original
+/// format sealed everything at once, so it is decrypted in memory.
+fn payload_reader<R: Read + 'static>(header: Option<&Header>, mut payload: R, keys: &mut KeyCache) -> Result<Box<dyn Read>, Box<dyn std::error::Error>> {
+    if let Some(header) = header {
+        let key = header.unwrap_key(keys)?;
+        return Ok(Box::new(DecryptReader::new(payload, &key, &header.nonce_prefix, header.payload_aad())));
+    }
+    let mut data = Vec::new();
+    payload.read_to_end(&mut data)?;
     if data.len() < 16 + 24 {
         return Err("Input file too short to contain a valid salt and nonce.".into());
     }
-    let salt = &data[..16];
-    let nonce = &data[16..16+24];
-    let ciphertext = &data[16+24..];
-    
-    let password = prompt_password("Enter password: ")?;
-    let key = derive_key_from_password(&password, salt)?;
-    
+    let salt = data[..16].try_into().expect("length checked above");
+    let key = keys.key(&salt, &KdfParams::LEGACY)?;
     let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
-    let plaintext = cipher.decrypt(GenericArray::from_slice(nonce), ciphertext)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    fs::write(output_path, plaintext)?;
+    let plaintext = cipher.decrypt(GenericArray::from_slice(&data[16..16+24]), &data[16+24..])
+        .map_err(|_| "Decryption failed: the file is corrupted or has been modified.")?;
+    Ok(Box::new(io::Cursor::new(plaintext)))
+}
+
+/// Decrypts the file at `input_path` and writes the plaintext to `output_path`,
+/// or to the file name stored in the metadata when no output path is given.
+/// A directory archive is extracted into a new directory at that path.
+/// Files in the original
+/// [salt (16 bytes)] || [nonce (24 bytes)] || [ciphertext] format have nothing
+/// to identify them, so they are only accepted when `legacy` is set.
+fn decrypt_file(input_path: &str, output_path: Option<&str>, action: MetadataAction, legacy: bool, keys: &mut KeyCache) -> Result<(), Box<dyn std::error::Error>> {
+    if output_path == Some(STDIO) && action == MetadataAction::Restore {
+        return Err("--restore-metadata cannot be used when writing to standard output.".into());
+    }
+    if let Some(output_path) = output_path {
+        refuse_same_file(input_path, output_path)?;
+    }
+    let mut input = BufReader::new(open_input(input_path)?);
+    let mut prefix = Vec::with_capacity(HEADER_LEN);
+    (&mut input).take(HEADER_LEN as u64).read_to_end(&mut prefix)?;
+    let header = Header::parse(&prefix)?;
+    if header.is_none() && !legacy {
+        return Err("This is not a chapas file. If it was encrypted by a version of chapas without a file header, pass --legacy.".into());
+    }
+    let has_metadata = header.as_ref().is_some_and(|h| h.kind != KIND_DATA);
+    let is_archive = header.as_ref().is_some_and(|h| h.kind == KIND_ARCHIVE);
+    if !has_metadata && (action != MetadataAction::Ignore || output_path.is_none()) {
+        return Err("This file has no stored metadata (it was encrypted from standard input or by a version of chapas without a file header).".into());
+    }
+    if is_archive && output_path == Some(STDIO) {
+        return Err("This file holds a directory archive, which cannot be written to standard output.".into());
+    }
+
+    // Anything read past the header belongs to the payload.
+    let rest = prefix.split_off(if header.is_some() { HEADER_LEN } else { 0 });
+    let mut reader = payload_reader(header.as_ref(), io::Cursor::new(rest).chain(input), keys)?;
+    if !has_metadata {
+        return write_plaintext(&mut reader, output_path.expect("checked above"), false);
+    }
+
+    let metadata = Metadata::read_from(&mut reader)?;
+    if action == MetadataAction::Show {
+        // Only the chunks holding the metadata have been authenticated at this point.
+        println!("{}", metadata);
+        if is_archive {
+            println!("Contents: directory archive");
+        }
+        return Ok(());
+    }
+    let path = match output_path {
+        Some(path) => path,
+        None => metadata.safe_name("an output file")?,
+    };
+    if is_archive {
+        // The tree is extracted into a new directory, which is removed again on failure.
+        archive::unpack(&mut reader, Path::new(path)).map_err(|e| e.to_string())?;
+    } else {
+        write_plaintext(&mut reader, path, output_path.is_none())?;
+    }
+    if output_path.is_none() {
+        println!("Decrypted to {}.", path);
+    }
+    if action == MetadataAction::Restore {
+        metadata.apply(path)?;
+    }
     Ok(())
 }
 
+/// Authenticates all of the file at `path` without writing any plaintext.
+/// A data key that cannot be unwrapped means a wrong password; a payload that
+/// fails afterwards means the file is damaged. Files in the original format
+/// cannot tell the two apart.
+fn verify_file(path: &Path, legacy: bool, keys: &mut KeyCache) -> Verdict {
+    let mut input = match fs::File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return Verdict::Failed(e.to_string()),
+    };
+    let mut prefix = Vec::with_capacity(HEADER_LEN);
+    if let Err(e) = (&mut input).take(HEADER_LEN as u64).read_to_end(&mut prefix) {
+        return Verdict::Failed(e.to_string());
+    }
+    let header = match Header::parse(&prefix) {
+        Ok(None) if !legacy => return Verdict::Skipped("not a chapas file; pass --legacy for files without a header".into()),
+        Ok(header) => header,
+        // A bad header is damage if the file is ours, and otherwise not our business.
+        Err(e) if prefix.starts_with(MAGIC) => return Verdict::Corrupt(e.to_string()),
+        Err(e) => return Verdict::Skipped(e.to_string()),
+    };
+    if let Some(Err(e)) = header.as_ref().map(|header| header.unwrap_key(keys)) {
+        return Verdict::WrongKey(e.to_string());
+    }
+
+    let rest = prefix.split_off(if header.is_some() { HEADER_LEN } else { 0 });
+    let result = payload_reader(header.as_ref(), io::Cursor::new(rest).chain(input), keys)
+        .and_then(|mut reader| Ok(io::copy(&mut reader, &mut io::sink())?));
+    match result {
+        Ok(_) => Verdict::Ok,
+        Err(e) if header.is_some() => Verdict::Corrupt(e.to_string()),
+        Err(e) => Verdict::Failed(e.to_string()),
+    }
+}
+
+/// Changes the password of the envelope-format file at `path` without
+/// re-encrypting its contents: the data key is re-wrapped under a new salt
+/// and password. The file is copied with the new header to a temporary file
+/// next to it, which is synced and renamed over the original, so a crash
+/// leaves either the old or the new header, never a damaged one. The Argon2id
+/// parameters are kept unless new ones are given.
+fn rekey_file(path: &str, kdf: &KdfOptions, policy: &Policy, current: &PasswordSource, new: &PasswordSource) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut prefix = Vec::with_capacity(HEADER_LEN);
+    (&mut file).take(HEADER_LEN as u64).read_to_end(&mut prefix)?;
+    let mut header = Header::parse(&prefix)?
+        .ok_or("This file uses the original format without a data key; decrypt and re-encrypt it first.")?;
+    let new_kdf = kdf.apply(header.kdf)?;
+
+    let old_password = match current {
+        PasswordSource::Prompt => prompt_password("Enter current password: ")?,
+        source => source.read(false)?,
+    };
+    let data_key = header.unwrap_key(&mut KeyCache::with_password(old_password))?;
+    let new_password = match new {
+        PasswordSource::Prompt => prompt_new_password("Enter new password: ", "Re-enter new password: ")?,
+        source => source.read(false)?,
+    };
+    policy.check(&new_password)?;
+    header.kdf = new_kdf;
+    header.wrap_key(&mut KeyCache::with_password(new_password), &data_key)?;
+
+    let temp = TempFile::create(Path::new(path))?;
+    let mut output = BufWriter::new(OpenOptions::new().write(true).open(temp.path())?);
+    output.write_all(&header.encode())?;
+    io::copy(&mut file, &mut output)?;
+    output.flush()?;
+    drop(output);
+    temp.copy_permissions()?;
+    temp.commit()?;
+    Ok(())
+}
+
+/// Suffix of the files written by `-E --batch`, removed again by `-D --batch`.
+const BATCH_SUFFIX: &str = ".enc";
+
+/// Output path for `input` in batch mode: "<input>.enc" when encrypting, and
+/// `input` without its ".enc" suffix when decrypting. Existing files are
+/// never replaced.
+fn batch_output(encrypt: bool, input: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = if encrypt {
+        format!("{}{}", input.trim_end_matches('/'), BATCH_SUFFIX)
+    } else {
+        input.strip_suffix(BATCH_SUFFIX).filter(|stem| !stem.is_empty())
+            .ok_or_else(|| format!("cannot name the output: the file name does not end in {}", BATCH_SUFFIX))?
+            .to_string()
+    };
+    if fs::symlink_metadata(&output).is_ok() {
+        return Err(format!("{} already exists", output).into());
+    }
+    Ok(output)
+}
+
+/// Passes every input in turn to `process`, which returns the path it wrote,
+/// reports each one, and fails if any of them failed. All inputs share one
+/// password; files encrypted together share one salt, so the Argon2id key is
+/// derived only once for them, both when encrypting and when they are
+/// decrypted together later.
+fn run_batch<F>(inputs: &[&str], keys: &mut KeyCache, mut process: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&str, &mut KeyCache) -> Result<String, Box<dyn std::error::Error>>,
+{
+    // Read the password once up front rather than while the first file is processed.
+    keys.password()?;
+    let mut failed = 0;
+    for &input in inputs {
+        match process(input, keys) {
+            Ok(output) if output == input => println!("OK      {} (in place)", input),
+            Ok(output) => println!("OK      {} -> {}", input, output),
+            Err(e) => {
+                println!("FAILED  {}: {}", input, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} of {} files processed successfully.", inputs.len() - failed, inputs.len());
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, inputs.len()).into());
+    }
+    Ok(())
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("Error: expected usage: {} -E <input> <output> [--label <text>]   (- for standard input or output)", program);
+    eprintln!("       {} -D <input> <output> [--restore-metadata]", program);
+    eprintln!("       {} -D <input> --restore-metadata   (write to the stored file name)", program);
+    eprintln!("       {} -D <input> --show-metadata      (print the stored name, size, mode and mtime)", program);
+    eprintln!("       {} -D <input> <output> --legacy    (file from a version of chapas without a file header)", program);
+    eprintln!("       {} -E --batch <input>...           (write each input to <input>.enc)", program);
+    eprintln!("       {} -D --batch <input.enc>...       (write each input to its name without .enc)", program);
+    eprintln!("       {} [-E|-D] --in-place <file>       (replace the file once the result is complete; combines with --batch)", program);
+    eprintln!("       {} -V <file or directory>          (check that files authenticate, without writing plaintext)", program);
+    eprintln!("       {} -R <file>   (change the password of an encrypted file)", program);
+    eprintln!("Argon2id options for -E and -R: --memory <KiB> --iterations <n> --lanes <n>");
+    eprintln!("Password strength options for -E and -R: --min-score <0-4> (default {}) | --allow-weak", DEFAULT_MIN_SCORE);
+    eprintln!("Password options for -E, -D, -V and -R: --password-file <path> | --password-env <name> | --password-fd <n>");
+    eprintln!("New password options for -R: --new-password-file <path> | --new-password-env <name> | --new-password-fd <n>");
+    std::process::exit(1);
+}
+
+/// Sets `source` from a password option (`--password-file` and so on, or the
+/// same with `--new-` for -R) and its value, exiting with usage on a repeated
+/// or malformed option.
+fn set_password_source(source: &mut PasswordSource, option: &str, value: Option<&String>, program: &str) {
+    let family = if option.starts_with("--new-") { "--new-password" } else { "--password" };
+    if !matches!(source, PasswordSource::Prompt) {
+        eprintln!("Error: only one of {0}-file, {0}-env and {0}-fd may be given.", family);
+        usage(program);
+    }
+    let Some(value) = value else { usage(program) };
+    *source = match &option[family.len()..] {
+        "-file" => PasswordSource::File(value.clone()),
+        "-env" => PasswordSource::Env(value.clone()),
+        _ => match value.parse() {
+            Ok(fd) => PasswordSource::Fd(fd),
+            Err(_) => {
+                eprintln!("Error: {}-fd expects a file descriptor number.", family);
+                usage(program);
+            }
+        },
+    };
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
+    let flag = args.get(1).map(String::as_str).unwrap_or("");
 
-    // Expect exactly 4 arguments: program, flag, input, output.
-    if args.len() != 4 {
-        eprintln!("Error: expected usage: {} [-E|-D] <input> <output>", args[0]);
-        std::process::exit(1);
+    // Split the remaining arguments into file paths and options.
+    let mut paths = Vec::new();
+    let mut label = None;
+    let mut restore_metadata = false;
+    let mut show_metadata = false;
+    let mut legacy = false;
+    let mut batch = false;
+    let mut in_place = false;
+    let mut kdf = KdfOptions::default();
+    let mut policy = Policy { min_score: DEFAULT_MIN_SCORE, allow_weak: false };
+    let mut password = PasswordSource::Prompt;
+    let mut new_password = PasswordSource::Prompt;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--label" if flag == "-E" => match rest.next() {
+                Some(text) => label = Some(text.as_str()),
+                None => usage(&args[0]),
+            },
+            "--restore-metadata" if flag == "-D" => restore_metadata = true,
+            "--show-metadata" if flag == "-D" => show_metadata = true,
+            "--legacy" if flag == "-D" || flag == "-V" => legacy = true,
+            "--batch" if flag == "-E" || flag == "-D" => batch = true,
+            "--in-place" if flag == "-E" || flag == "-D" => in_place = true,
+            "--memory" | "--iterations" | "--lanes" if flag == "-E" || flag == "-R" => {
+                let value = match rest.next().map(|v| v.parse::<u32>()) {
+                    Some(Ok(value)) => value,
+                    _ => {
+                        eprintln!("Error: {} expects a positive integer.", arg);
+                        usage(&args[0]);
+                    }
+                };
+                match arg.as_str() {
+                    "--memory" => kdf.memory_kib = Some(value),
+                    "--iterations" => kdf.iterations = Some(value),
+                    _ => kdf.lanes = Some(value),
+                }
+            }
+            "--min-score" if flag == "-E" || flag == "-R" => match rest.next().map(|v| v.parse::<u8>()) {
+                Some(Ok(score)) if score <= MAX_SCORE => policy.min_score = score,
+                _ => {
+                    eprintln!("Error: --min-score expects a score from 0 to {}.", MAX_SCORE);
+                    usage(&args[0]);
+                }
+            },
+            "--allow-weak" if flag == "-E" || flag == "-R" => policy.allow_weak = true,
+            "--password-file" | "--password-env" | "--password-fd" if matches!(flag, "-E" | "-D" | "-V" | "-R") => {
+                set_password_source(&mut password, arg, rest.next(), &args[0]);
+            }
+            "--new-password-file" | "--new-password-env" | "--new-password-fd" if flag == "-R" => {
+                set_password_source(&mut new_password, arg, rest.next(), &args[0]);
+            }
+            option if option.starts_with("--") => {
+                eprintln!("Error: unknown option {}.", option);
+                usage(&args[0]);
+            }
+            path => paths.push(path),
+        }
     }
 
-    let flag = &args[1];
-    let input = &args[2];
-    let output = &args[3];
+    // -E takes an input and an output, -R a single file. -D may omit the
+    // output when it is taken from (or not needed because of) the metadata.
+    // In batch mode every path is an input, and in place the input is also
+    // the output.
+    let valid = match flag {
+        _ if batch => !paths.is_empty() && !paths.contains(&STDIO) && !show_metadata,
+        _ if in_place => paths.len() == 1 && paths[0] != STDIO && !show_metadata,
+        "-R" | "-V" => paths.len() == 1,
+        "-D" if show_metadata => paths.len() == 1 && !restore_metadata,
+        "-D" if restore_metadata => paths.len() == 1 || paths.len() == 2,
+        _ => paths.len() == 2,
+    };
+    if !valid {
+        usage(&args[0]);
+    }
 
-    match flag.as_str() {
-        "-E" => encrypt_file(input, output)?,
-        "-D" => decrypt_file(input, output)?,
+    let action = if show_metadata {
+        MetadataAction::Show
+    } else if restore_metadata {
+        MetadataAction::Restore
+    } else {
+        MetadataAction::Ignore
+    };
+    let encrypt = flag == "-E";
+    let mut keys = KeyCache::new(password, encrypt, if encrypt { Some(policy) } else { None });
+    match flag {
+        "-E" | "-D" if batch => {
+            let kdf = kdf.apply(KdfParams::DEFAULT)?;
+            run_batch(&paths, &mut keys, |input, keys| {
+                let output = if in_place { input.to_string() } else { batch_output(encrypt, input)? };
+                match (encrypt, in_place) {
+                    (true, true) => encrypt_in_place(input, label, kdf, keys)?,
+                    (true, false) => { encrypt_file(input, &output, label, kdf, keys)?; }
+                    (false, true) => decrypt_in_place(input, action, legacy, keys)?,
+                    (false, false) => decrypt_file(input, Some(&output), action, legacy, keys)?,
+                }
+                Ok(output)
+            })?
+        }
+        "-E" if in_place => encrypt_in_place(paths[0], label, kdf.apply(KdfParams::DEFAULT)?, &mut keys)?,
+        "-D" if in_place => decrypt_in_place(paths[0], action, legacy, &mut keys)?,
+        "-E" => { encrypt_file(paths[0], paths[1], label, kdf.apply(KdfParams::DEFAULT)?, &mut keys)?; }
+        "-D" => decrypt_file(paths[0], paths.get(1).copied(), action, legacy, &mut keys)?,
+        "-V" => {
+            keys.password()?;
+            verify::run(Path::new(paths[0]), |path| verify_file(path, legacy, &mut keys))?;
+        }
+        "-R" => rekey_file(paths[0], &kdf, &policy, &keys.source, &new_password)?,
         _ => {
-            eprintln!("Error: unknown flag {}. Use -E for encryption or -D for decryption.", flag);
+            eprintln!("Error: unknown flag {}. Use -E for encryption, -D for decryption, -V to verify or -R to change the password.", flag);
             std::process::exit(1);
         }
     }
 
     Ok(())
 }
-
@@ -0,0 +1,38 @@
+use common::password;
+use rpassword::prompt_password;
+
+/// Where the password comes from. Without an explicit option it is prompted
+/// for on the terminal.
+pub enum PasswordSource {
+    Prompt,
+    /// `--password-env NAME`: the value of an environment variable.
+    Env(String),
+    /// `--password-file PATH`: the first line of a file.
+    File(String),
+    /// `--password-fd N`: the first line read from an open file descriptor.
+    Fd(i32),
+}
+
+/// Asks for a new password twice and checks that both entries match.
+pub fn prompt_new_password(prompt: &str, confirm: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let password1 = prompt_password(prompt)?;
+    let password2 = prompt_password(confirm)?;
+    if password1 != password2 {
+        return Err("Passwords do not match.".into());
+    }
+    Ok(password1)
+}
+
+impl PasswordSource {
+    /// Reads the password. A prompted password is asked for twice when
+    /// `confirm` is set (i.e. when encrypting).
+    pub fn read(&self, confirm: bool) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            PasswordSource::Prompt if confirm => prompt_new_password("Enter password: ", "Re-enter password: "),
+            PasswordSource::Prompt => Ok(prompt_password("Enter password: ")?),
+            PasswordSource::Env(name) => password::from_env(name),
+            PasswordSource::File(path) => password::from_file(path),
+            PasswordSource::Fd(fd) => password::from_fd(*fd),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use crate::metadata::{file_mode, from_unix_time, set_mode, set_modified, to_unix_time};
+
+/// Version of the archive stream layout, written as its first byte.
+const ARCHIVE_VERSION: u8 = 1;
+
+const ENTRY_END: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+const ENTRY_FILE: u8 = 2;
+const ENTRY_SYMLINK: u8 = 3;
+
+/// Extracted entries never get setuid, setgid or sticky bits.
+const MODE_MASK: u32 = 0o777;
+
+// Archive stream layout, all integers big-endian:
+//   [version (1)] then entries, terminated by a single [type = 0] byte.
+// Each entry is [type (1)][path length (2)][path][mode (4)] followed by
+//   directory: nothing
+//   file:      [mtime seconds (8)][mtime nanoseconds (4)][size (8)][contents]
+//   symlink:   [target length (2)][target]
+// Paths are relative to the archived directory, with '/' separators.
+// Directories are always written before their contents.
+
+fn write_str<W: Write>(out: &mut W, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let len = u16::try_from(value.len()).map_err(|_| format!("Path is longer than 65535 bytes: {}", value))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn read_array<R: Read, const N: usize>(input: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_str<R: Read>(input: &mut R) -> Result<String, Box<dyn std::error::Error>> {
+    let len = u16::from_be_bytes(read_array(input)?) as usize;
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| "Corrupted archive: path is not valid UTF-8.".into())
+}
+
+/// Writes the directory tree under `root` to `out` as an archive stream.
+/// Special files (sockets, devices, FIFOs) are skipped with a warning.
+pub fn pack<W: Write>(root: &Path, out: &mut W) -> Result<(), Box<dyn std::error::Error>> {
+    out.write_all(&[ARCHIVE_VERSION])?;
+    pack_dir(root, "", out)?;
+    out.write_all(&[ENTRY_END])?;
+    Ok(())
+}
+
+fn pack_dir<W: Write>(dir: &Path, prefix: &str, out: &mut W) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().into_string()
+            .map_err(|_| format!("File name is not valid UTF-8: {}", path.display()))?;
+        let archived = format!("{}{}", prefix, name);
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target.to_str()
+                .ok_or_else(|| format!("Symbolic link target is not valid UTF-8: {}", path.display()))?;
+            out.write_all(&[ENTRY_SYMLINK])?;
+            write_str(out, &archived)?;
+            out.write_all(&file_mode(&metadata).to_be_bytes())?;
+            write_str(out, target)?;
+        } else if file_type.is_dir() {
+            out.write_all(&[ENTRY_DIR])?;
+            write_str(out, &archived)?;
+            out.write_all(&file_mode(&metadata).to_be_bytes())?;
+            pack_dir(&path, &format!("{}/", archived), out)?;
+        } else if file_type.is_file() {
+            let (mtime_secs, mtime_nanos) = to_unix_time(metadata.modified()?);
+            out.write_all(&[ENTRY_FILE])?;
+            write_str(out, &archived)?;
+            out.write_all(&file_mode(&metadata).to_be_bytes())?;
+            out.write_all(&mtime_secs.to_be_bytes())?;
+            out.write_all(&mtime_nanos.to_be_bytes())?;
+            out.write_all(&metadata.len().to_be_bytes())?;
+            let copied = io::copy(&mut fs::File::open(&path)?.take(metadata.len()), out)?;
+            if copied != metadata.len() {
+                return Err(format!("{} changed while it was being archived.", path.display()).into());
+            }
+        } else {
+            eprintln!("Skipping special file {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Turns an archived path into a path below `dest`, rejecting absolute
+/// paths and any `.` or `..` component.
+fn safe_join(dest: &Path, archived: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if archived.starts_with('/') || archived.starts_with('\\') {
+        return Err(format!("Refusing to extract absolute path {:?}.", archived).into());
+    }
+    let mut path = dest.to_path_buf();
+    for part in archived.split('/') {
+        if part == ".." {
+            return Err(format!("Refusing to extract path containing '..': {:?}.", archived).into());
+        }
+        // Also catches empty and "." parts, and drive or root prefixes on Windows.
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) if c == part => path.push(part),
+            _ => return Err(format!("Refusing to extract invalid path {:?}.", archived).into()),
+        }
+    }
+    Ok(path)
+}
+
+/// Checks that every directory between `dest` and `path` was created by this
+/// extraction as a real directory, so no entry can be written through a
+/// symbolic link extracted earlier.
+fn check_parents(dest: &Path, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == dest {
+            return Ok(());
+        }
+        match fs::symlink_metadata(dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            _ => return Err(format!("Refusing to extract {}: its parent is not an extracted directory.", path.display()).into()),
+        }
+        parent = dir.parent();
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, path: &Path) -> io::Result<()> {
+    eprintln!("Skipping symbolic link {} (not supported on this platform)", path.display());
+    Ok(())
+}
+
+/// Recreates the tree from an archive stream in the new directory `dest`,
+/// which must not exist yet. Nothing outside `dest` is ever written and no
+/// existing file is replaced. If extraction fails, `dest` is removed again.
+pub fn unpack<R: Read>(input: &mut R, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir(dest).map_err(|e| format!("Cannot create directory {}: {}", dest.display(), e))?;
+    let result = unpack_into(input, dest);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(dest);
+    }
+    result
+}
+
+fn unpack_into<R: Read>(input: &mut R, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let [version] = read_array(input)?;
+    if version != ARCHIVE_VERSION {
+        return Err(format!("Unsupported archive version {}.", version).into());
+    }
+
+    // Directory modes are applied last, so read-only directories can still be filled.
+    let mut dir_modes = Vec::new();
+    loop {
+        let [entry_type] = read_array(input)?;
+        if entry_type == ENTRY_END {
+            break;
+        }
+        let archived = read_str(input)?;
+        let path = safe_join(dest, &archived)?;
+        check_parents(dest, &path)?;
+        let mode = u32::from_be_bytes(read_array(input)?) & MODE_MASK;
+
+        match entry_type {
+            ENTRY_DIR => {
+                fs::create_dir(&path)?;
+                dir_modes.push((path, mode));
+            }
+            ENTRY_FILE => {
+                let mtime_secs = i64::from_be_bytes(read_array(input)?);
+                let mtime_nanos = u32::from_be_bytes(read_array(input)?);
+                let size = u64::from_be_bytes(read_array(input)?);
+                if mtime_nanos >= 1_000_000_000 {
+                    return Err("Corrupted archive: invalid modification time.".into());
+                }
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)
+                    .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+                let copied = io::copy(&mut input.take(size), &mut file)?;
+                if copied != size {
+                    return Err("Corrupted archive: file contents are truncated.".into());
+                }
+                drop(file);
+                set_modified(&path, from_unix_time(mtime_secs, mtime_nanos))?;
+                set_mode(&path, mode)?;
+            }
+            ENTRY_SYMLINK => {
+                let target = read_str(input)?;
+                create_symlink(&target, &path)
+                    .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+            }
+            _ => return Err(format!("Corrupted archive: unknown entry type {}.", entry_type).into()),
+        }
+    }
+
+    // Reading to the end also authenticates the final part of the stream.
+    if io::copy(input, &mut io::sink())? != 0 {
+        return Err("Corrupted archive: unexpected data after the last entry.".into());
+    }
+    for (path, mode) in dir_modes.into_iter().rev() {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory under the system temporary directory.
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("archive-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn safe_join_accepts_relative_paths() {
+        let dest = Path::new("dest");
+        assert_eq!(safe_join(dest, "a").unwrap(), dest.join("a"));
+        assert_eq!(safe_join(dest, "a/b/c.txt").unwrap(), dest.join("a").join("b").join("c.txt"));
+    }
+
+    #[test]
+    fn safe_join_rejects_parent_components() {
+        for archived in ["..", "../x", "a/..", "a/../../x", "a/b/../../.."] {
+            assert!(safe_join(Path::new("dest"), archived).is_err(), "{:?}", archived);
+        }
+    }
+
+    #[test]
+    fn safe_join_rejects_absolute_and_invalid_paths() {
+        for archived in ["/etc/passwd", "\\x", "", ".", "./a", "a/./b", "a//b", "a/"] {
+            assert!(safe_join(Path::new("dest"), archived).is_err(), "{:?}", archived);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_parents_rejects_symlinked_parent() {
+        let root = scratch_dir();
+        let (dest, outside) = (root.join("dest"), root.join("outside"));
+        fs::create_dir(&dest).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::create_dir(dest.join("real")).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+
+        assert!(check_parents(&dest, &dest.join("top.txt")).is_ok());
+        assert!(check_parents(&dest, &dest.join("real").join("x")).is_ok());
+        assert!(check_parents(&dest, &dest.join("link").join("x")).is_err());
+        assert!(check_parents(&dest, &dest.join("missing").join("x")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unpack_does_not_write_through_extracted_symlink() {
+        let root = scratch_dir();
+        let (dest, outside) = (root.join("dest"), root.join("outside"));
+        fs::create_dir(&outside).unwrap();
+
+        let mut archive = vec![ARCHIVE_VERSION, ENTRY_SYMLINK];
+        write_str(&mut archive, "link").unwrap();
+        archive.extend_from_slice(&0o777u32.to_be_bytes());
+        write_str(&mut archive, outside.to_str().unwrap()).unwrap();
+        archive.push(ENTRY_FILE);
+        write_str(&mut archive, "link/evil").unwrap();
+        archive.extend_from_slice(&0o644u32.to_be_bytes());
+        archive.extend_from_slice(&0i64.to_be_bytes());
+        archive.extend_from_slice(&0u32.to_be_bytes());
+        archive.extend_from_slice(&4u64.to_be_bytes());
+        archive.extend_from_slice(b"evil");
+        archive.push(ENTRY_END);
+
+        assert!(unpack(&mut archive.as_slice(), &dest).is_err());
+        assert!(!outside.join("evil").exists());
+        assert!(!dest.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
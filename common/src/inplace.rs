@@ -0,0 +1,257 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::{STDIO, create_output, read_full};
+
+/// A temporary file in the same directory as the file it will replace, so
+/// that the final rename is atomic. Until `commit` succeeds the original is
+/// left untouched, and the temporary file is removed when this is dropped.
+pub struct TempFile {
+    path: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl TempFile {
+    /// Creates an empty temporary file with a unique name next to `target`,
+    /// which must be a regular file. It is only readable by its owner until
+    /// `copy_permissions` is called.
+    pub fn create(target: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !fs::symlink_metadata(target)?.is_file() {
+            return Err(format!("{} is not a regular file and cannot be replaced in place.", target.display()).into());
+        }
+        Self::create_next_to(target, true)
+    }
+
+    fn create_next_to(target: &Path, private: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = target.file_name()
+            .ok_or_else(|| format!("{} is not a file name.", target.display()))?
+            .to_string_lossy();
+        let dir = target.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if private {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        loop {
+            let path = dir.join(format!(".{}.{:016x}.tmp", name, rand::random::<u64>()));
+            match options.open(&path) {
+                Ok(_) => return Ok(TempFile { path, target: target.to_path_buf(), committed: false }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("Cannot create a temporary file in {}: {}", dir.display(), e).into()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gives the temporary file the permissions of the file it replaces.
+    pub fn copy_permissions(&self) -> io::Result<()> {
+        fs::set_permissions(&self.path, fs::metadata(&self.target)?.permissions())
+    }
+
+    /// Flushes the temporary file to disk, renames it over the target and
+    /// syncs the directory, so that after a crash either the old or the new
+    /// file is found, complete.
+    pub fn commit(mut self) -> io::Result<()> {
+        // fsync works on a read-only handle on Unix; a read-only mode may
+        // already have been applied.
+        OpenOptions::new().write(true).open(&self.path)
+            .or_else(|_| fs::File::open(&self.path))?
+            .sync_all()?;
+        fs::rename(&self.path, &self.target)?;
+        self.committed = true;
+        #[cfg(unix)]
+        {
+            let dir = self.target.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Decrypted output that only takes its place once all of it has been
+/// written. A file is written under a temporary name next to it and renamed
+/// over `path` by `commit`, so a chunk that fails to authenticate leaves an
+/// existing file untouched. Standard output, and outputs that are not regular
+/// files such as /dev/null, cannot be held back and are written directly.
+pub struct PendingOutput {
+    writer: BufWriter<Box<dyn Write>>,
+    temp: Option<TempFile>,
+    /// A name reserved for a new file, removed again unless committed.
+    reserved: Option<PathBuf>,
+}
+
+impl PendingOutput {
+    /// Opens `path` for output; "-" writes to standard output. An existing
+    /// file keeps its permissions. With `create_new`, `path` must not exist:
+    /// its name is reserved by an empty file until the output is committed.
+    pub fn create(path: &str, create_new: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let direct = |writer| PendingOutput { writer: BufWriter::new(writer), temp: None, reserved: None };
+        let cannot_create = |e: &dyn std::fmt::Display| format!("Cannot create {}: {}", path, e);
+        if path == STDIO {
+            return Ok(direct(create_output(path)?));
+        }
+        let mut target = PathBuf::from(path);
+        let mut reserved = None;
+        if create_new {
+            OpenOptions::new().write(true).create_new(true).open(&target).map_err(|e| cannot_create(&e))?;
+            reserved = Some(target.clone());
+        } else {
+            match fs::metadata(&target) {
+                Ok(meta) if !meta.is_file() => return Ok(direct(create_output(path)?)),
+                // Replace the file a symbolic link points to, not the link.
+                Ok(_) => target = fs::canonicalize(&target)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(cannot_create(&e).into()),
+            }
+        }
+
+        // Built first, so that a failure from here on removes the reserved name.
+        let mut output = PendingOutput { writer: BufWriter::new(Box::new(io::sink())), temp: None, reserved };
+        let temp = TempFile::create_next_to(&target, false).map_err(|e| cannot_create(&e))?;
+        if output.reserved.is_none() && target.exists() {
+            temp.copy_permissions()?;
+        }
+        output.writer = BufWriter::new(Box::new(OpenOptions::new().write(true).open(temp.path())?));
+        output.temp = Some(temp);
+        Ok(output)
+    }
+
+    /// Flushes the output and, for a file, syncs it and renames it into place.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if let Some(temp) = self.temp.take() {
+            temp.commit()?;
+        }
+        self.reserved = None;
+        Ok(())
+    }
+}
+
+impl Write for PendingOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for PendingOutput {
+    fn drop(&mut self) {
+        if let Some(path) = &self.reserved {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Whether `a` and `b` name the same existing file, through links or not.
+/// Writing to one of them while reading the other would destroy the data.
+pub fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let (meta_a, meta_b) = match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(meta_a), Ok(meta_b)) => (meta_a, meta_b),
+        (Err(e), _) | (_, Err(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(meta_a.dev() == meta_b.dev() && meta_a.ino() == meta_b.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (meta_a, meta_b);
+        Ok(fs::canonicalize(a)? == fs::canonicalize(b)?)
+    }
+}
+
+/// Fails if writing `output_path` would overwrite `input_path` before it has
+/// been read. Standard input and output never clash.
+pub fn refuse_same_file(input_path: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if input_path != "-" && output_path != "-" && same_file(Path::new(input_path), Path::new(output_path))? {
+        return Err(format!("{} is both the input and the output; use --in-place to replace a file with its result.",
+                           input_path).into());
+    }
+    Ok(())
+}
+
+/// Whether two readers yield exactly the same bytes.
+pub fn same_contents<A: Read, B: Read>(mut a: A, mut b: B) -> io::Result<bool> {
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = read_full(&mut a, &mut buf_a)?;
+        let m = read_full(&mut b, &mut buf_b)?;
+        if buf_a[..n] != buf_b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory under the system temporary directory.
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("inplace-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn pending_output_replaces_file_only_on_commit() {
+        let dir = scratch_dir();
+        let path = dir.join("out");
+        fs::write(&path, b"old").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut output = PendingOutput::create(path, false).unwrap();
+        output.write_all(b"partial").unwrap();
+        drop(output);
+        assert_eq!(fs::read(path).unwrap(), b"old");
+
+        let mut output = PendingOutput::create(path, false).unwrap();
+        output.write_all(b"new").unwrap();
+        output.commit().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pending_output_releases_reserved_name() {
+        let dir = scratch_dir();
+        let path = dir.join("out");
+        let path = path.to_str().unwrap();
+
+        let output = PendingOutput::create(path, true).unwrap();
+        assert!(PendingOutput::create(path, true).is_err());
+        drop(output);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let mut output = PendingOutput::create(path, true).unwrap();
+        output.write_all(b"new").unwrap();
+        output.commit().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
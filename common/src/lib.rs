@@ -0,0 +1,49 @@
+//! Code shared by the encryption tools in this repository, so that a fix to
+//! any of it reaches every tool at once.
+
+use std::fs;
+use std::io::{self, Read, Write};
+
+pub mod archive;
+pub mod inplace;
+pub mod metadata;
+pub mod password;
+pub mod stream;
+pub mod strength;
+pub mod verify;
+
+/// Reads until `buf` is full or the reader reaches end of file, returning the
+/// number of bytes read.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Path that stands for standard input or standard output.
+pub const STDIO: &str = "-";
+
+/// Opens `path` for reading; "-" reads standard input.
+pub fn open_input(path: &str) -> io::Result<Box<dyn Read>> {
+    if path == STDIO {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+}
+
+/// Creates (or truncates) `path` for writing; "-" writes to standard output.
+pub fn create_output(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == STDIO {
+        Ok(Box::new(io::stdout().lock()))
+    } else {
+        Ok(Box::new(fs::File::create(path)?))
+    }
+}
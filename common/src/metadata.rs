@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bound on the encoded length of a metadata block: two length-prefixed
+/// strings of at most 64 KiB plus the fixed-size fields.
+const MAX_ENCODED_LEN: usize = 2 * (2 + u16::MAX as usize) + 8 + 4 + 8 + 4;
+
+/// File attributes stored, encrypted, at the start of the plaintext.
+///
+/// Encoded as [length (4)] followed by
+/// [name length (2)][name][size (8)][mode (4)][mtime seconds (8)][mtime nanoseconds (4)]
+/// [label length (2)][label], all integers big-endian. The mtime seconds are
+/// signed, relative to the Unix epoch.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+    pub label: String,
+}
+
+/// Permission bits of a file, approximated from the read-only flag where
+/// the platform has no Unix modes.
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Splits a timestamp into seconds relative to the Unix epoch (negative
+/// before it) and a non-negative nanosecond part.
+pub fn to_unix_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            if before.subsec_nanos() == 0 {
+                (-(before.as_secs() as i64), 0)
+            } else {
+                (-(before.as_secs() as i64) - 1, 1_000_000_000 - before.subsec_nanos())
+            }
+        }
+    }
+}
+
+/// Inverse of `to_unix_time`.
+pub fn from_unix_time(secs: i64, nanos: u32) -> SystemTime {
+    let nanos = Duration::from_nanos(nanos as u64);
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+    }
+}
+
+/// Sets the modification time of a file or directory.
+pub fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    let file = if path.is_dir() {
+        fs::File::open(path)?
+    } else {
+        fs::OpenOptions::new().write(true).open(path)?
+    };
+    file.set_modified(time)
+}
+
+fn push_str(out: &mut Vec<u8>, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let len = u16::try_from(value.len()).map_err(|_| "Metadata field is longer than 65535 bytes.")?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// Splits `len` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+    if data.len() < len {
+        return Err("Corrupted metadata block.".into());
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Ok(head)
+}
+
+fn take_array<const N: usize>(data: &mut &[u8]) -> Result<[u8; N], Box<dyn std::error::Error>> {
+    let mut buf = [0u8; N];
+    buf.copy_from_slice(take(data, N)?);
+    Ok(buf)
+}
+
+fn take_str(data: &mut &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let len = u16::from_be_bytes(take_array(data)?) as usize;
+    String::from_utf8(take(data, len)?.to_vec()).map_err(|_| "Corrupted metadata block.".into())
+}
+
+/// A payload that ends inside the block is corrupted; any other error (such
+/// as a chunk failing to authenticate) is reported as it is.
+fn read_error(e: io::Error) -> Box<dyn std::error::Error> {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        "Corrupted metadata block.".into()
+    } else {
+        e.to_string().into()
+    }
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Metadata {
+    /// Collects the attributes of the file at `path`.
+    pub fn from_file(path: &str, label: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let metadata = fs::metadata(path)?;
+        let name = Path::new(path).file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (mtime_secs, mtime_nanos) = to_unix_time(metadata.modified()?);
+        Ok(Metadata {
+            name,
+            // A directory's own length is meaningless; its contents go into the archive.
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            mode: file_mode(&metadata),
+            mtime_secs,
+            mtime_nanos,
+            label: label.unwrap_or("").to_string(),
+        })
+    }
+
+    /// Serializes the block, including its length prefix.
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut body = Vec::new();
+        push_str(&mut body, &self.name)?;
+        body.extend_from_slice(&self.size.to_be_bytes());
+        body.extend_from_slice(&self.mode.to_be_bytes());
+        body.extend_from_slice(&self.mtime_secs.to_be_bytes());
+        body.extend_from_slice(&self.mtime_nanos.to_be_bytes());
+        push_str(&mut body, &self.label)?;
+
+        let mut out = Vec::with_capacity(4 + body.len());
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend(body);
+        Ok(out)
+    }
+
+    /// Reads a block written by `encode` from the start of the decrypted plaintext.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).map_err(read_error)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_ENCODED_LEN {
+            return Err("Corrupted metadata block.".into());
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).map_err(read_error)?;
+
+        let mut data = body.as_slice();
+        let metadata = Metadata {
+            name: take_str(&mut data)?,
+            size: u64::from_be_bytes(take_array(&mut data)?),
+            mode: u32::from_be_bytes(take_array(&mut data)?),
+            mtime_secs: i64::from_be_bytes(take_array(&mut data)?),
+            mtime_nanos: u32::from_be_bytes(take_array(&mut data)?),
+            label: take_str(&mut data)?,
+        };
+        if !data.is_empty() || metadata.mtime_nanos >= 1_000_000_000 {
+            return Err("Corrupted metadata block.".into());
+        }
+        Ok(metadata)
+    }
+
+    /// The stored file name, if it can safely be used as an output path in
+    /// the current directory (a single, normal path component). `alternative`
+    /// tells the user how to name the output instead, such as "--output".
+    pub fn safe_name(&self, alternative: &str) -> Result<&str, Box<dyn std::error::Error>> {
+        let name = self.name.as_str();
+        let is_plain = !name.is_empty()
+            && Path::new(name).file_name().is_some_and(|n| n == name)
+            && !name.contains(['/', '\\']);
+        if !is_plain {
+            return Err(format!("The stored file name {:?} cannot be used as an output path; pass {}.", name, alternative).into());
+        }
+        Ok(name)
+    }
+
+    /// Applies the stored permissions and modification time to `path`.
+    pub fn apply(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Path::new(path);
+        // Set the time first: a read-only mode would prevent opening the file for writing.
+        set_modified(path, from_unix_time(self.mtime_secs, self.mtime_nanos))?;
+        set_mode(path, self.mode)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.mtime_secs.div_euclid(86_400));
+        let secs = self.mtime_secs.rem_euclid(86_400);
+        writeln!(f, "Name:     {}", self.name)?;
+        writeln!(f, "Size:     {} bytes", self.size)?;
+        writeln!(f, "Mode:     {:04o}", self.mode)?;
+        writeln!(f, "Modified: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+                 year, month, day, secs / 3600, secs / 60 % 60, secs % 60)?;
+        if self.label.is_empty() {
+            write!(f, "Label:    (none)")
+        } else {
+            write!(f, "Label:    {}", self.label)
+        }
+    }
+}
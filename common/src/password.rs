@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+
+/// Returns the first line of `data`, without its line terminator.
+fn first_line(data: &str) -> &str {
+    let line = data.split('\n').next().unwrap_or("");
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Reads a password from the environment variable `name`.
+pub fn from_env(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    env::var(name)
+        .map_err(|e| format!("Failed to read password from environment variable {}: {}", name, e).into())
+}
+
+/// Reads a password from the first line of the file at `path`.
+pub fn from_file(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read password file {}: {}", path, e))?;
+    Ok(first_line(&data).to_string())
+}
+
+/// Reads a password from the first line of open file descriptor `fd`, which
+/// is closed afterwards.
+#[cfg(unix)]
+pub fn from_fd(fd: i32) -> Result<String, Box<dyn std::error::Error>> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 {
+        return Err(format!("Invalid file descriptor {}.", fd).into());
+    }
+    // SAFETY: the descriptor was handed to us by the caller for exactly this
+    // purpose; taking ownership closes it once the password has been read.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut data = String::new();
+    file.read_to_string(&mut data)
+        .map_err(|e| format!("Failed to read password from file descriptor {}: {}", fd, e))?;
+    Ok(first_line(&data).to_string())
+}
+
+#[cfg(not(unix))]
+pub fn from_fd(_fd: i32) -> Result<String, Box<dyn std::error::Error>> {
+    Err("--password-fd is only supported on Unix platforms.".into())
+}
@@ -0,0 +1,325 @@
+//! Chunked STREAM encryption of file payloads. chakey and chapas use
+//! XChaCha20-Poly1305 with the default chunk size; the AES tool passes in
+//! the cipher and chunk size its file header records.
+
+use std::io::{self, Read, Write};
+use std::ops::Sub;
+use crate::read_full;
+use chacha20poly1305::aead::{self, AeadInPlace};
+use chacha20poly1305::aead::stream::{Decryptor, DecryptorBE32, Encryptor, EncryptorBE32, NonceSize, StreamPrimitive};
+use chacha20poly1305::aead::generic_array::{ArrayLength, GenericArray};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+
+/// Default number of plaintext bytes sealed into each chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the random nonce prefix stored in the header. The STREAM BE32
+/// construction fills the remaining 5 bytes of the 24-byte XChaCha20 nonce
+/// with a 32-bit chunk counter and a last-chunk flag.
+pub const NONCE_PREFIX_LEN: usize = 24 - 5;
+
+/// Length of the tag appended to every chunk; 16 bytes for each AEAD the
+/// tools use.
+const TAG_LEN: usize = 16;
+
+/// The sealing half of a STREAM construction over some AEAD, so that the
+/// cipher can be chosen at run time.
+pub trait ChunkEncryptor {
+    fn encrypt_next(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()>;
+    fn encrypt_last(self: Box<Self>, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()>;
+}
+
+/// The opening half of a STREAM construction over some AEAD.
+pub trait ChunkDecryptor {
+    fn decrypt_next(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()>;
+    fn decrypt_last(self: Box<Self>, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()>;
+}
+
+impl<A, S> ChunkEncryptor for Encryptor<A, S>
+where
+    A: AeadInPlace,
+    S: StreamPrimitive<A>,
+    A::NonceSize: Sub<S::NonceOverhead>,
+    NonceSize<A, S>: ArrayLength<u8>,
+{
+    fn encrypt_next(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()> {
+        self.encrypt_next_in_place(aad, buffer)
+    }
+
+    fn encrypt_last(self: Box<Self>, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()> {
+        (*self).encrypt_last_in_place(aad, buffer)
+    }
+}
+
+impl<A, S> ChunkDecryptor for Decryptor<A, S>
+where
+    A: AeadInPlace,
+    S: StreamPrimitive<A>,
+    A::NonceSize: Sub<S::NonceOverhead>,
+    NonceSize<A, S>: ArrayLength<u8>,
+{
+    fn decrypt_next(&mut self, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()> {
+        self.decrypt_next_in_place(aad, buffer)
+    }
+
+    fn decrypt_last(self: Box<Self>, aad: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()> {
+        (*self).decrypt_last_in_place(aad, buffer)
+    }
+}
+
+/// Encrypts everything written to it as a sequence of STREAM chunks.
+///
+/// Plaintext is buffered until a full chunk is available; a chunk is only
+/// sealed as "not last" once more data arrives, so `finish` must be called to
+/// seal the final (possibly short or empty) chunk. Every chunk is
+/// authenticated against the same associated data (the file header).
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<Box<dyn ChunkEncryptor>>,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    aad: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Encrypts with XChaCha20-Poly1305 in chunks of `CHUNK_SIZE` bytes.
+    pub fn new(inner: W, key: &[u8; 32], nonce_prefix: &[u8; NONCE_PREFIX_LEN], aad: Vec<u8>) -> Self {
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(key));
+        let encryptor = EncryptorBE32::from_aead(cipher, GenericArray::from_slice(nonce_prefix));
+        Self::with_encryptor(inner, Box::new(encryptor), CHUNK_SIZE, aad)
+    }
+
+    /// Encrypts with `encryptor` in chunks of `chunk_size` bytes.
+    pub fn with_encryptor(inner: W, encryptor: Box<dyn ChunkEncryptor>, chunk_size: usize, aad: Vec<u8>) -> Self {
+        EncryptWriter {
+            inner,
+            encryptor: Some(encryptor),
+            buffer: Vec::with_capacity(chunk_size + TAG_LEN),
+            chunk_size,
+            aad,
+        }
+    }
+
+    /// Seals the buffered plaintext as an intermediate chunk.
+    fn seal_chunk(&mut self) -> io::Result<()> {
+        let encryptor = self.encryptor.as_mut()
+            .ok_or_else(|| io::Error::other("Stream already finished"))?;
+        encryptor.encrypt_next(&self.aad, &mut self.buffer)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Seals the remaining plaintext as the last chunk and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let encryptor = self.encryptor.take()
+            .ok_or_else(|| io::Error::other("Stream already finished"))?;
+        encryptor.encrypt_last(&self.aad, &mut self.buffer)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.buffer.len() == self.chunk_size {
+            self.seal_chunk()?;
+        }
+        let take = (self.chunk_size - self.buffer.len()).min(buf.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a sequence of STREAM chunks, yielding plaintext one chunk at a time.
+///
+/// Each chunk is authenticated before any of its bytes are returned. The
+/// chunk counter in the nonce rejects reordered or dropped chunks, and reading
+/// one byte past a full chunk tells us whether it must be the last one, so a
+/// file truncated on a chunk boundary fails instead of silently ending early.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<Box<dyn ChunkDecryptor>>,
+    buffer: Vec<u8>,
+    pos: usize,
+    carry: Option<u8>,
+    chunk_size: usize,
+    aad: Vec<u8>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Decrypts XChaCha20-Poly1305 chunks of `CHUNK_SIZE` bytes.
+    pub fn new(inner: R, key: &[u8; 32], nonce_prefix: &[u8; NONCE_PREFIX_LEN], aad: Vec<u8>) -> Self {
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(key));
+        let decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(nonce_prefix));
+        Self::with_decryptor(inner, Box::new(decryptor), CHUNK_SIZE, aad)
+    }
+
+    /// Decrypts chunks of `chunk_size` bytes with `decryptor`.
+    pub fn with_decryptor(inner: R, decryptor: Box<dyn ChunkDecryptor>, chunk_size: usize, aad: Vec<u8>) -> Self {
+        DecryptReader {
+            inner,
+            decryptor: Some(decryptor),
+            buffer: Vec::with_capacity(chunk_size + TAG_LEN + 1),
+            pos: 0,
+            carry: None,
+            chunk_size,
+            aad,
+        }
+    }
+
+    /// Reads and authenticates the next chunk into `buffer`.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let sealed_len = self.chunk_size + TAG_LEN;
+        self.buffer.clear();
+        self.buffer.resize(sealed_len + 1, 0);
+        self.pos = 0;
+
+        let mut filled = 0;
+        if let Some(byte) = self.carry.take() {
+            self.buffer[0] = byte;
+            filled = 1;
+        }
+        filled += read_full(&mut self.inner, &mut self.buffer[filled..])?;
+
+        let failed = || io::Error::new(io::ErrorKind::InvalidData,
+                                       "Decryption failed: the file is corrupted, truncated or has been modified.");
+        let result = if filled > sealed_len {
+            // More data follows, so this must be an intermediate chunk.
+            self.carry = Some(self.buffer[sealed_len]);
+            self.buffer.truncate(sealed_len);
+            let decryptor = self.decryptor.as_mut().ok_or_else(failed)?;
+            decryptor.decrypt_next(&self.aad, &mut self.buffer)
+        } else {
+            self.buffer.truncate(filled);
+            let decryptor = self.decryptor.take().ok_or_else(failed)?;
+            decryptor.decrypt_last(&self.aad, &mut self.buffer)
+        };
+        if result.is_err() {
+            // Never hand out unauthenticated bytes, even if the caller keeps reading.
+            self.buffer.clear();
+            self.decryptor = None;
+            return Err(failed());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = (self.buffer.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE_PREFIX: [u8; NONCE_PREFIX_LEN] = [9; NONCE_PREFIX_LEN];
+    const SEALED: usize = CHUNK_SIZE + TAG_LEN;
+
+    fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), &KEY, &NONCE_PREFIX, b"header".to_vec());
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptReader::new(ciphertext, &KEY, &NONCE_PREFIX, b"header".to_vec());
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Three full chunks of different bytes: two sealed as intermediate, the
+    /// third as last.
+    fn three_chunks() -> Vec<u8> {
+        let plaintext: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i / CHUNK_SIZE) as u8).collect();
+        let ciphertext = encrypt(&plaintext);
+        assert_eq!(ciphertext.len(), 3 * SEALED);
+        ciphertext
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(decrypt(&encrypt(&plaintext)).unwrap(), plaintext, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn truncation_at_chunk_boundary_is_detected() {
+        let ciphertext = three_chunks();
+        for chunks in 1..3 {
+            assert!(decrypt(&ciphertext[..chunks * SEALED]).is_err(), "{} chunks kept", chunks);
+        }
+        assert!(decrypt(&[]).is_err());
+    }
+
+    #[test]
+    fn dropped_or_reordered_chunks_are_detected() {
+        let ciphertext = three_chunks();
+        let chunk = |i: usize| &ciphertext[i * SEALED..(i + 1) * SEALED];
+        assert!(decrypt(&[chunk(0), chunk(2)].concat()).is_err());
+        assert!(decrypt(&[chunk(1), chunk(2)].concat()).is_err());
+        assert!(decrypt(&[chunk(1), chunk(0), chunk(2)].concat()).is_err());
+        assert!(decrypt(&[chunk(0), chunk(2), chunk(1)].concat()).is_err());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let mut ciphertext = three_chunks();
+        ciphertext[SEALED + 100] ^= 1;
+        assert!(decrypt(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn chunk_size_is_configurable() {
+        const SMALL: usize = 16;
+        let cipher = || XChaCha20Poly1305::new(GenericArray::from_slice(&KEY));
+        let nonce = GenericArray::from_slice(&NONCE_PREFIX);
+        let plaintext = [0x42; 3 * SMALL];
+        let mut writer = EncryptWriter::with_encryptor(Vec::new(), Box::new(EncryptorBE32::from_aead(cipher(), nonce)),
+                                                       SMALL, b"header".to_vec());
+        writer.write_all(&plaintext).unwrap();
+        let ciphertext = writer.finish().unwrap();
+        assert_eq!(ciphertext.len(), 3 * (SMALL + TAG_LEN));
+
+        let decrypt = |ciphertext: &[u8]| {
+            let decryptor = Box::new(DecryptorBE32::from_aead(cipher(), nonce));
+            let mut reader = DecryptReader::with_decryptor(ciphertext, decryptor, SMALL, b"header".to_vec());
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).map(|_| decrypted)
+        };
+        assert_eq!(decrypt(&ciphertext).unwrap(), plaintext);
+        assert!(decrypt(&ciphertext[..2 * (SMALL + TAG_LEN)]).is_err());
+    }
+
+    #[test]
+    fn wrong_associated_data_is_rejected() {
+        let ciphertext = encrypt(b"secret");
+        let mut reader = DecryptReader::new(ciphertext.as_slice(), &KEY, &NONCE_PREFIX, b"other".to_vec());
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Common passwords, most frequent first; a password's rank in this list is
+/// the number of guesses an attacker needs to reach it.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// Strength required of new passwords unless `--min-score` says otherwise.
+pub const DEFAULT_MIN_SCORE: u8 = 3;
+
+/// Highest score, for passwords needing 10^10 guesses or more.
+pub const MAX_SCORE: u8 = 4;
+
+/// Only this many characters are analysed, which keeps the estimate fast;
+/// any password that long is strong unless its start is already weak.
+const MAX_ANALYSED: usize = 64;
+
+/// Keyboard rows, for walks such as "qwerty" or "lkjhg".
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Characters commonly substituted for letters ("p@ssw0rd").
+const LEET: [(char, &str); 13] = [
+    ('4', "a"), ('@', "a"), ('8', "b"), ('(', "c"), ('3', "e"), ('6', "g"), ('9', "g"),
+    ('1', "il"), ('!', "i"), ('|', "il"), ('0', "o"), ('$', "s"), ('5', "s"),
+];
+
+/// The kinds of pattern the estimator recognises, in the order their
+/// feedback is preferred.
+#[derive(Clone, Copy, PartialEq)]
+enum Pattern {
+    Dictionary,
+    Keyboard,
+    Sequence,
+    Repeat,
+    Year,
+    BruteForce,
+}
+
+/// A pattern covering `start..end` of the password and the number of guesses
+/// needed to find it.
+struct Match {
+    start: usize,
+    end: usize,
+    guesses: f64,
+    pattern: Pattern,
+}
+
+/// Result of estimating the strength of a password.
+pub struct Estimate {
+    /// 0 (trivial) to `MAX_SCORE` (very hard to guess), from the same
+    /// thresholds as zxcvbn: fewer than 10^3, 10^6, 10^8 and 10^10 guesses.
+    pub score: u8,
+    /// Whether the whole password is in the common-password list.
+    pub common: bool,
+    /// What makes the password weak, if a pattern was found.
+    pub warning: Option<&'static str>,
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Extra guesses for the capitalisation of a word that was matched in lower case.
+fn case_variations(word: &[char]) -> f64 {
+    let upper = word.iter().filter(|c| c.is_uppercase()).count();
+    let lower = word.iter().filter(|c| c.is_lowercase()).count();
+    if upper == 0 {
+        return 1.0;
+    }
+    // "Password", "passworD" and "PASSWORD" are tried early.
+    if lower == 0 || (upper == 1 && (word[0].is_uppercase() || word[word.len() - 1].is_uppercase())) {
+        return 2.0;
+    }
+    (1..=upper.min(lower)).map(|i| binomial(upper + lower, i)).sum()
+}
+
+fn lower(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Matches of the common-password list anywhere in the password, allowing
+/// for capitalisation and letter substitutions.
+fn dictionary_matches(chars: &[char], matches: &mut Vec<Match>) {
+    for (rank, word) in COMMON_PASSWORDS.lines().enumerate() {
+        let word: Vec<char> = word.chars().collect();
+        if word.len() < 3 || word.len() > chars.len() {
+            continue;
+        }
+        for start in 0..=chars.len() - word.len() {
+            let candidate = &chars[start..start + word.len()];
+            let mut substitutions = 0;
+            let found = candidate.iter().zip(&word).all(|(&c, &w)| {
+                if lower(c) == w {
+                    return true;
+                }
+                let substituted = LEET.iter().any(|&(from, to)| from == c && to.contains(w));
+                substitutions += substituted as i32;
+                substituted
+            });
+            if found {
+                let guesses = (rank + 1) as f64 * case_variations(candidate) * 2f64.powi(substitutions);
+                matches.push(Match { start, end: start + word.len(), guesses, pattern: Pattern::Dictionary });
+            }
+        }
+    }
+}
+
+/// Runs of at least three keys along a keyboard row, in either direction.
+fn keyboard_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let lowered: Vec<char> = chars.iter().map(|&c| lower(c)).collect();
+    for start in 0..lowered.len() {
+        for end in start + 3..=lowered.len() {
+            let run: String = lowered[start..end].iter().collect();
+            let reversed: String = lowered[start..end].iter().rev().collect();
+            if KEYBOARD_ROWS.iter().any(|row| row.contains(&run) || row.contains(&reversed)) {
+                let guesses = 40.0 * (end - start) as f64;
+                matches.push(Match { start, end, guesses, pattern: Pattern::Keyboard });
+            }
+        }
+    }
+}
+
+/// Runs of at least three characters whose codes step by the same small
+/// amount, such as "abc", "7531" or "zyx".
+fn sequence_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let mut start = 0;
+    while start + 2 < chars.len() {
+        let delta = chars[start + 1] as i64 - chars[start] as i64;
+        let mut end = start + 2;
+        while end < chars.len() && chars[end] as i64 - chars[end - 1] as i64 == delta {
+            end += 1;
+        }
+        if delta != 0 && delta.abs() <= 5 && end - start >= 3 {
+            let first = chars[start];
+            let base = if "aAzZ019".contains(first) {
+                4.0
+            } else if first.is_ascii_digit() {
+                10.0
+            } else {
+                26.0
+            };
+            let direction = if delta > 0 { 1.0 } else { 2.0 };
+            matches.push(Match { start, end, guesses: base * direction * (end - start) as f64, pattern: Pattern::Sequence });
+            start = end - 1;
+        } else {
+            start += 1;
+        }
+    }
+}
+
+/// Runs of at least three identical characters, and blocks repeated back to
+/// back. Each run is matched once, with its shortest repeating block.
+fn repeat_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let mut start = 0;
+    while start < chars.len() {
+        let run = (1..=(chars.len() - start) / 2).find_map(|block| {
+            let mut end = start + block;
+            while end + block <= chars.len() && chars[end..end + block] == chars[start..start + block] {
+                end += block;
+            }
+            let count = (end - start) / block;
+            (count >= if block == 1 { 3 } else { 2 }).then_some((block, end, count))
+        });
+        let Some((block, end, count)) = run else {
+            start += 1;
+            continue;
+        };
+        let base = if block == 1 {
+            let c = chars[start];
+            if c.is_ascii_digit() { 10.0 } else if c.is_alphabetic() { 26.0 } else { 33.0 }
+        } else {
+            minimum_guesses(&chars[start..start + block]).0
+        };
+        matches.push(Match { start, end, guesses: base * count as f64, pattern: Pattern::Repeat });
+        start = end;
+    }
+}
+
+/// Four-digit years close to the present.
+fn year_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let current_year = 1970 + (now / 31_556_952) as i64;
+    for start in 0..chars.len().saturating_sub(3) {
+        let digits: String = chars[start..start + 4].iter().collect();
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let year: i64 = digits.parse().expect("four ASCII digits");
+        if (1900..=2099).contains(&year) {
+            let guesses = (year - current_year).abs().max(20) as f64;
+            matches.push(Match { start, end: start + 4, guesses, pattern: Pattern::Year });
+        }
+    }
+}
+
+/// The cheapest way to cover the whole password with patterns and single
+/// brute-forced characters (10 guesses each), as the number of guesses and
+/// the most telling pattern used.
+fn minimum_guesses(chars: &[char]) -> (f64, Pattern) {
+    let mut matches = Vec::new();
+    dictionary_matches(chars, &mut matches);
+    keyboard_matches(chars, &mut matches);
+    sequence_matches(chars, &mut matches);
+    repeat_matches(chars, &mut matches);
+    year_matches(chars, &mut matches);
+
+    // best[i]: log10 of the fewest guesses for the first i characters, and
+    // the patterns on that path.
+    let mut best: Vec<(f64, Vec<Pattern>)> = vec![(f64::INFINITY, Vec::new()); chars.len() + 1];
+    best[0].0 = 0.0;
+    for end in 1..=chars.len() {
+        let (log, patterns) = &best[end - 1];
+        let mut candidate = (log + 1.0, patterns.clone());
+        candidate.1.push(Pattern::BruteForce);
+        for m in matches.iter().filter(|m| m.end == end) {
+            let log = best[m.start].0 + m.guesses.max(1.0).log10();
+            if log < candidate.0 {
+                let mut patterns = best[m.start].1.clone();
+                patterns.push(m.pattern);
+                candidate = (log, patterns);
+            }
+        }
+        best[end] = candidate;
+    }
+    let (log, patterns) = &best[chars.len()];
+    let pattern = patterns.iter().copied()
+        .filter(|&p| p != Pattern::BruteForce)
+        .min_by_key(|&p| p as u8)
+        .unwrap_or(Pattern::BruteForce);
+    (10f64.powf(*log), pattern)
+}
+
+/// Estimates how many guesses an attacker who tries common passwords and
+/// patterns first would need, in the manner of zxcvbn.
+pub fn estimate(password: &str) -> Estimate {
+    let chars: Vec<char> = password.chars().take(MAX_ANALYSED).collect();
+    let lowered = password.to_lowercase();
+    let common = COMMON_PASSWORDS.lines().any(|word| word == lowered);
+    let (guesses, pattern) = minimum_guesses(&chars);
+
+    let log = guesses.log10();
+    let score = match log {
+        log if log < 3.0 => 0,
+        log if log < 6.0 => 1,
+        log if log < 8.0 => 2,
+        log if log < 10.0 => 3,
+        _ => MAX_SCORE,
+    };
+    let warning = match pattern {
+        _ if chars.is_empty() => Some("it is empty"),
+        Pattern::Dictionary => Some("it is or contains a common password"),
+        Pattern::Keyboard => Some("it contains a row of keys such as \"qwerty\""),
+        Pattern::Sequence => Some("it contains a sequence such as \"abc\" or \"6543\""),
+        Pattern::Repeat => Some("it contains repeated characters or words"),
+        Pattern::Year => Some("it contains a recent year"),
+        Pattern::BruteForce if score < MAX_SCORE => Some("it is too short"),
+        Pattern::BruteForce => None,
+    };
+    Estimate { score, common, warning }
+}
+
+/// Which passwords may be set.
+#[derive(Clone, Copy)]
+pub struct Policy {
+    /// Lowest acceptable score, from 0 to `MAX_SCORE`.
+    pub min_score: u8,
+    /// Accept a weak password after a warning instead of refusing it.
+    pub allow_weak: bool,
+}
+
+impl Policy {
+    /// Checks a new password. This is cheap and runs before any key derivation.
+    pub fn check(&self, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let estimate = estimate(password);
+        let reason = if estimate.common {
+            "it is in the list of common passwords".to_string()
+        } else if estimate.score < self.min_score {
+            format!("its strength score is {} of {}, below the required {}{}", estimate.score, MAX_SCORE, self.min_score,
+                    estimate.warning.map(|w| format!(", because {}", w)).unwrap_or_default())
+        } else {
+            return Ok(());
+        };
+        if self.allow_weak {
+            eprintln!("Warning: using a weak password because of --allow-weak: {}.", reason);
+            return Ok(());
+        }
+        Err(format!("Password rejected: {}. Choose a longer, less predictable password, or pass --allow-weak.", reason).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(password: &str) -> (u8, Option<&'static str>) {
+        let estimate = estimate(password);
+        (estimate.score, estimate.warning)
+    }
+
+    const DICTIONARY: Option<&str> = Some("it is or contains a common password");
+    const KEYBOARD: Option<&str> = Some("it contains a row of keys such as \"qwerty\"");
+    const SEQUENCE: Option<&str> = Some("it contains a sequence such as \"abc\" or \"6543\"");
+    const REPEAT: Option<&str> = Some("it contains repeated characters or words");
+
+    #[test]
+    fn common_passwords_score_zero() {
+        for password in ["password", "123456", "Password", "PASSWORD"] {
+            let estimate = estimate(password);
+            assert!(estimate.common, "{}", password);
+            assert_eq!((estimate.score, estimate.warning), (0, DICTIONARY), "{}", password);
+        }
+        assert_eq!(score("ilovemonkey"), (1, DICTIONARY));
+    }
+
+    #[test]
+    fn leet_variants_match_the_dictionary() {
+        let estimate = estimate("dr@g0n");
+        assert!(!estimate.common);
+        assert_eq!((estimate.score, estimate.warning), (0, DICTIONARY));
+        assert_eq!(score("P4ssw0rd!"), (0, DICTIONARY));
+    }
+
+    #[test]
+    fn keyboard_walks_are_weak() {
+        assert_eq!(score("lkjhgfds"), (0, KEYBOARD));
+        assert_eq!(score("mnbvcx"), (0, KEYBOARD));
+        assert_eq!(score("poiuytlkjhg"), (1, KEYBOARD));
+    }
+
+    #[test]
+    fn sequences_and_repeats_are_weak() {
+        assert_eq!(score("9876543210"), (0, SEQUENCE));
+        assert_eq!(score("aaaaaaaaaaaa"), (0, REPEAT));
+        assert_eq!(score("abcabcabcabc"), (0, REPEAT));
+        assert_eq!(score("xkcdxkcdxkcd"), (1, REPEAT));
+    }
+
+    #[test]
+    fn strong_passphrases_score_four() {
+        for password in ["correct horse battery staple", "velvet-orbit-canyon-7", "Tr0ub4dor&3"] {
+            assert_eq!(score(password), (MAX_SCORE, None), "{}", password);
+        }
+    }
+
+    #[test]
+    fn short_and_empty_passwords_are_weak() {
+        assert_eq!(score("kT7#qz"), (2, Some("it is too short")));
+        assert_eq!(score(""), (0, Some("it is empty")));
+    }
+
+    #[test]
+    fn policy_enforces_min_score() {
+        let default = Policy { min_score: DEFAULT_MIN_SCORE, allow_weak: false };
+        assert!(default.check("correct horse battery staple").is_ok());
+        let error = default.check("kT7#qz").unwrap_err().to_string();
+        assert!(error.contains("its strength score is 2 of 4, below the required 3"), "{}", error);
+
+        let lenient = Policy { min_score: 2, allow_weak: false };
+        assert!(lenient.check("kT7#qz").is_ok());
+        let strict = Policy { min_score: MAX_SCORE, allow_weak: false };
+        assert!(strict.check("kT7#qz!Lw2").is_ok());
+        assert!(strict.check("xkcdxkcdxkcd").is_err());
+    }
+
+    #[test]
+    fn policy_rejects_common_passwords_at_any_min_score() {
+        let anything = Policy { min_score: 0, allow_weak: false };
+        assert!(anything.check("kT7#qz").is_ok());
+        let error = anything.check("password").unwrap_err().to_string();
+        assert!(error.contains("it is in the list of common passwords"), "{}", error);
+    }
+
+    #[test]
+    fn allow_weak_accepts_weak_passwords() {
+        let policy = Policy { min_score: DEFAULT_MIN_SCORE, allow_weak: true };
+        for password in ["password", "kT7#qz", ""] {
+            assert!(policy.check(password).is_ok(), "{}", password);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Outcome of checking one file.
+pub enum Verdict {
+    Ok,
+    /// The key was unlocked but the payload failed to authenticate, so the
+    /// file has been damaged or modified.
+    Corrupt(String),
+    /// The password or keyfile does not unlock the file.
+    WrongKey(String),
+    /// The file could not be read, or its format cannot tell a wrong key
+    /// from damage.
+    Failed(String),
+    /// Not a file of this tool. Only tolerated for files found in a directory.
+    Skipped(String),
+}
+
+/// Regular files below `dir`, in sorted order. Symbolic links are not followed.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Checks `path` with `check`, or every regular file below it when it is a
+/// directory, printing one line per file. A directory scan ends with a
+/// summary. Fails if any file is not OK; files of other kinds found in a
+/// directory are reported but do not count as failures.
+pub fn run<F>(path: &Path, mut check: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(&Path) -> Verdict,
+{
+    let is_dir = fs::metadata(path)?.is_dir();
+    let mut files = Vec::new();
+    if is_dir {
+        collect_files(path, &mut files)?;
+    } else {
+        files.push(path.to_path_buf());
+    }
+
+    let (mut ok, mut corrupt, mut wrong_key, mut failed, mut skipped) = (0, 0, 0, 0, 0);
+    for file in &files {
+        let verdict = match check(file) {
+            Verdict::Skipped(reason) if !is_dir => Verdict::Failed(reason),
+            verdict => verdict,
+        };
+        let (status, reason) = match verdict {
+            Verdict::Ok => { ok += 1; ("OK", None) }
+            Verdict::Corrupt(reason) => { corrupt += 1; ("CORRUPT", Some(reason)) }
+            Verdict::WrongKey(reason) => { wrong_key += 1; ("WRONG KEY", Some(reason)) }
+            Verdict::Failed(reason) => { failed += 1; ("FAILED", Some(reason)) }
+            Verdict::Skipped(reason) => { skipped += 1; ("SKIPPED", Some(reason)) }
+        };
+        match reason {
+            Some(reason) => println!("{:<10} {}: {}", status, file.display(), reason),
+            None => println!("{:<10} {}", status, file.display()),
+        }
+    }
+    if is_dir {
+        println!("{} files checked: {} OK, {} corrupt, {} wrong key, {} failed, {} skipped.",
+                 files.len(), ok, corrupt, wrong_key, failed, skipped);
+    }
+
+    let bad = corrupt + wrong_key + failed;
+    if bad > 0 {
+        return Err(format!("{} of {} files failed verification.", bad, files.len() - skipped).into());
+    }
+    Ok(())
+}